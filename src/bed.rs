@@ -0,0 +1,333 @@
+use crate::{Coord, Error};
+use flate2::bufread::MultiGzDecoder;
+use std::io::{BufRead, BufReader, Chain, Cursor, Read};
+use std::num::IntErrorKind;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BedRecord {
+    pub chrom: String,
+    pub start: Coord,
+    pub end: Coord,
+    pub name: String,
+    pub score: f64,
+    pub values: Vec<String>,
+}
+
+impl BedRecord {
+    /// Looks up the value of `key` in the name column, read as a list of `;` separated
+    /// attributes written either GTF-style (`key "value"`) or as `key=value`
+    pub fn name_attribute(&self, key: &str) -> Option<&str> {
+        self.name.split(';').find_map(|attribute| {
+            let (k, v) = attribute
+                .trim()
+                .split_once(|c: char| c == '=' || c.is_whitespace())?;
+            (k == key).then(|| v.trim().trim_matches('"'))
+        })
+    }
+}
+
+/// Whether a line is a comment, a `track` or `browser` line or blank rather than a record
+pub(crate) fn is_header_line(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty()
+        || line.starts_with('#')
+        || line.starts_with("track")
+        || line.starts_with("browser")
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Appended to the error for a coordinate that overflows, pointing at the wider build
+#[cfg(not(feature = "u64-coords"))]
+const WIDER_COORDS_HINT: &str = ", build with the u64-coords feature for larger ones";
+#[cfg(feature = "u64-coords")]
+const WIDER_COORDS_HINT: &str = "";
+
+/// A reader that is decompressed on the fly when its first bytes turn out to be the
+/// magic number of gzip or zstd
+pub(crate) enum MaybeCompressed<R: BufRead> {
+    Plain(Peeked<R>),
+    Gzip(BufReader<MultiGzDecoder<Peeked<R>>>),
+    #[cfg(feature = "zstd")]
+    Zstd(BufReader<zstd::Decoder<'static, Peeked<R>>>),
+}
+
+/// A reader with the bytes that were read to detect the compression put back in front
+type Peeked<R> = Chain<Cursor<Vec<u8>>, R>;
+
+impl<R: BufRead> MaybeCompressed<R> {
+    /// Picks the decompressor from the first bytes. They are read until there are enough
+    /// to tell, however few each read hands over, and then put back in front of the rest,
+    /// so this works on pipes and FIFOs that cannot be rewound. Concatenated gzip members
+    /// and zstd frames are all read rather than stopping after the first
+    pub(crate) fn new(reader: R) -> Result<Self, Error> {
+        let mut reader = reader;
+        let mut start = Vec::with_capacity(ZSTD_MAGIC.len());
+        reader
+            .by_ref()
+            .take(ZSTD_MAGIC.len() as u64)
+            .read_to_end(&mut start)
+            .map_err(Error::Read)?;
+        let (is_gzip, is_zstd) = (start.starts_with(GZIP_MAGIC), start.starts_with(ZSTD_MAGIC));
+        let reader = Cursor::new(start).chain(reader);
+        if is_gzip {
+            return Ok(MaybeCompressed::Gzip(BufReader::new(MultiGzDecoder::new(
+                reader,
+            ))));
+        }
+        if is_zstd {
+            #[cfg(feature = "zstd")]
+            return zstd::Decoder::with_buffer(reader)
+                .map(|decoder| MaybeCompressed::Zstd(BufReader::new(decoder)))
+                .map_err(Error::Read);
+            #[cfg(not(feature = "zstd"))]
+            return Err(Error::Read(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the input is zstd-compressed, which needs a build with the zstd feature",
+            )));
+        }
+        Ok(MaybeCompressed::Plain(reader))
+    }
+
+    fn inner(&mut self) -> &mut dyn BufRead {
+        match self {
+            MaybeCompressed::Plain(reader) => reader,
+            MaybeCompressed::Gzip(reader) => reader,
+            #[cfg(feature = "zstd")]
+            MaybeCompressed::Zstd(reader) => reader,
+        }
+    }
+}
+
+impl<R: BufRead> Read for MaybeCompressed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner().read(buf)
+    }
+}
+
+impl<R: BufRead> BufRead for MaybeCompressed<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner().fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner().consume(amount)
+    }
+}
+
+/// A line of a BED file that is kept by [`BedParser::next_item`]
+#[derive(Debug)]
+pub enum BedItem {
+    Record(BedRecord),
+    /// A `#` comment line, without its line ending
+    Comment(String),
+}
+
+/// Iterates over the records of a BED file, skipping comments, `track` and `browser` lines
+/// and blank lines
+pub struct BedParser<R: BufRead> {
+    reader: R,
+    /// Reused for every line, so that reading a line does not allocate
+    line: String,
+    line_number: usize,
+    default_score: f64,
+}
+
+impl<R: BufRead> BedParser<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_default_score(reader, 0.0)
+    }
+
+    /// Uses `default_score` for records whose score is missing or not a number
+    pub fn with_default_score(reader: R, default_score: f64) -> Self {
+        BedParser {
+            reader,
+            line: String::new(),
+            line_number: 0,
+            default_score,
+        }
+    }
+
+    /// The 1-based number of the line that was read last
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    /// Reads the next record or `#` comment line, skipping `track` and `browser` lines and
+    /// blank lines. Iterating over the parser skips the comments as well
+    pub fn next_item(&mut self) -> Option<Result<BedItem, Error>> {
+        loop {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => self.line_number += 1,
+                Err(e) => return Some(Err(Error::Read(e))),
+            }
+            if self.line.trim_start().starts_with('#') {
+                let comment = self.line.trim_end_matches(['\n', '\r']).to_string();
+                return Some(Ok(BedItem::Comment(comment)));
+            }
+            if !is_header_line(&self.line) {
+                return Some(self.parse_line(&self.line).map(BedItem::Record));
+            }
+        }
+    }
+
+    /// Reads a coordinate, telling one that is too large apart from one that is not a
+    /// number at all
+    fn parse_coord(&self, column: &str, field: &str) -> Result<Coord, Error> {
+        field.parse::<Coord>().map_err(|e| {
+            let message = match e.kind() {
+                IntErrorKind::PosOverflow => format!(
+                    "The {} {} is larger than the largest supported coordinate {}{}",
+                    column,
+                    field,
+                    Coord::MAX,
+                    WIDER_COORDS_HINT
+                ),
+                _ => format!("Could not parse {} '{}'", column, field),
+            };
+            Error::parse(self.line_number, message)
+        })
+    }
+
+    fn parse_line(&self, line: &str) -> Result<BedRecord, Error> {
+        let fields: Vec<&str> = line.trim().split('\t').collect();
+        if fields.len() < 3 {
+            return Err(Error::parse(
+                self.line_number,
+                format!("Expected at least 3 columns but found {}", fields.len()),
+            ));
+        }
+        let chrom = fields[0].to_string();
+        let start = self.parse_coord("start", fields[1])?;
+        let end = self.parse_coord("end", fields[2])?;
+        let name = fields.get(3).map(|x| x.to_string()).unwrap_or_default();
+        let score = fields
+            .get(4)
+            .and_then(|x| x.parse::<f64>().ok())
+            .unwrap_or(self.default_score);
+        let values = fields
+            .get(5..)
+            .unwrap_or_default()
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        Ok(BedRecord {
+            chrom,
+            start,
+            end,
+            name,
+            values,
+            score,
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for BedParser<R> {
+    type Item = Result<BedRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_item()? {
+                Ok(BedItem::Record(record)) => return Some(Ok(record)),
+                Ok(BedItem::Comment(_)) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const INPUT: &str = "c\t0\t10\tn\t1\nc\t10\t20\tn\t2\n";
+
+    /// Reads `bytes` through a `BufReader` over a plain slice, which cannot seek, like a pipe
+    fn chroms_and_starts(bytes: &[u8]) -> Vec<(String, Coord)> {
+        let reader = MaybeCompressed::new(BufReader::new(bytes)).unwrap();
+        BedParser::new(reader)
+            .map(|record| record.map(|record| (record.chrom, record.start)))
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn gzip(text: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn plain_input_is_read_without_seeking() {
+        let expected = vec![("c".to_string(), 0), ("c".to_string(), 10)];
+        assert_eq!(chroms_and_starts(INPUT.as_bytes()), expected);
+    }
+
+    #[test]
+    fn gzip_input_is_read_without_seeking() {
+        let expected = vec![("c".to_string(), 0), ("c".to_string(), 10)];
+        assert_eq!(chroms_and_starts(&gzip(INPUT)), expected);
+    }
+
+    #[test]
+    fn concatenated_gzip_members_are_all_read() {
+        let mut bytes = gzip("a\t0\t10\tn\t1\n");
+        bytes.extend(gzip("b\t5\t10\tn\t1\n"));
+        let expected = vec![("a".to_string(), 0), ("b".to_string(), 5)];
+        assert_eq!(chroms_and_starts(&bytes), expected);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_input_is_read_without_seeking() {
+        let bytes = zstd::encode_all(INPUT.as_bytes(), 3).unwrap();
+        let expected = vec![("c".to_string(), 0), ("c".to_string(), 10)];
+        assert_eq!(chroms_and_starts(&bytes), expected);
+    }
+
+    #[test]
+    fn overflowing_coordinates_point_at_the_wider_build() {
+        let line = format!("c\t0\t{}0\tn\t1\n", Coord::MAX);
+        let error = BedParser::new(line.as_bytes()).next().unwrap().unwrap_err();
+        let Error::Parse { line: 1, message } = error else {
+            panic!("expected a parse error on line 1, got {:?}", error);
+        };
+        assert!(message.ends_with(WIDER_COORDS_HINT), "{}", message);
+    }
+
+    /// Hands over one byte per read, as a pipe might when its writer is slow
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some((&first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn compression_is_detected_from_reads_of_one_byte() {
+        let bytes = gzip(INPUT);
+        let reader = MaybeCompressed::new(BufReader::with_capacity(1, Trickle(&bytes))).unwrap();
+        assert!(matches!(reader, MaybeCompressed::Gzip(_)));
+        let records = BedParser::new(reader)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        let plain = BufReader::with_capacity(1, Trickle(b"c"));
+        let mut reader = MaybeCompressed::new(plain).unwrap();
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "c");
+    }
+}
@@ -0,0 +1,247 @@
+use crate::{Coord, OutputIndex, RecordWriter};
+use std::io::Write;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BedGraphRecord {
+    pub chrom: String,
+    pub start: Coord,
+    pub end: Coord,
+    pub value: f64,
+    /// The other columns of the BED record this came from, when they are kept for BED
+    /// output. Records made from several others, like bins and peaks, have none
+    pub columns: Option<Box<BedColumns>>,
+}
+
+/// The columns of a BED record besides the coordinates and the score
+#[derive(Debug, Clone, PartialEq)]
+pub struct BedColumns {
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+/// How values are written to the output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ValueFormat {
+    /// The standard float formatting. It round-trips but writes very large and very
+    /// small values out in full
+    #[default]
+    Plain,
+    /// The shortest string that parses back to exactly the same `f64` (including `-0`,
+    /// `inf` and `NaN`), switching to scientific notation when that is shorter
+    Lossless,
+    /// Rounded to this many significant digits, written out in full without an exponent,
+    /// e.g. `0.00123` and `12300` for 3
+    SigFigs(u8),
+}
+
+impl ValueFormat {
+    pub(crate) fn write<W: Write>(&self, writer: &mut W, value: f64) -> std::io::Result<()> {
+        match self {
+            ValueFormat::Plain => write!(writer, "{}", value),
+            ValueFormat::Lossless => {
+                // Both forms use the shortest digits that round-trip, they only differ in
+                // where the decimal point goes
+                let plain = value.to_string();
+                let scientific = format!("{:e}", value);
+                if scientific.len() < plain.len() {
+                    writer.write_all(scientific.as_bytes())
+                } else {
+                    writer.write_all(plain.as_bytes())
+                }
+            }
+            ValueFormat::SigFigs(_) if value == 0.0 || !value.is_finite() => {
+                write!(writer, "{}", value)
+            }
+            ValueFormat::SigFigs(digits) => {
+                // Scientific notation does the rounding, and tells where the point goes
+                // once rounding has possibly carried into a new digit
+                let digits = (*digits).max(1) as i32;
+                let scientific = format!("{:.*e}", (digits - 1) as usize, value);
+                let (mantissa, exponent) = scientific
+                    .split_once('e')
+                    .expect("scientific notation has an exponent");
+                let exponent: i32 = exponent.parse().expect("the exponent is a number");
+                let decimals = digits - 1 - exponent;
+                if decimals >= 0 {
+                    write!(writer, "{:.*}", decimals as usize, value)
+                } else {
+                    let zeros = "0".repeat(-decimals as usize);
+                    write!(writer, "{}{}", mantissa.replace('.', ""), zeros)
+                }
+            }
+        }
+    }
+}
+
+/// The track line written when no other header is given
+pub const DEFAULT_HEADER: &str = "track type=bedGraph";
+
+/// The width columns are padded to for pretty output, which fits coordinates up to the
+/// length of the largest human chromosome
+const PRETTY_WIDTH: usize = 10;
+
+/// Keeps track of how many bytes went through, for the offsets in the index
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct BedGraphWriter<W: Write> {
+    writer: CountingWriter<W>,
+    value_format: ValueFormat,
+    bed_columns: bool,
+    pretty: bool,
+    index: OutputIndex,
+}
+
+impl<W: Write> BedGraphWriter<W> {
+    pub fn new(writer: W) -> std::io::Result<Self> {
+        Self::with_value_format(writer, ValueFormat::default())
+    }
+
+    pub fn with_value_format(writer: W, value_format: ValueFormat) -> std::io::Result<Self> {
+        Self::with_header(writer, value_format, Some(DEFAULT_HEADER))
+    }
+
+    /// Starts the track with `header` instead of the default track line, or with no
+    /// header at all. A missing final newline is added
+    pub fn with_header(
+        writer: W,
+        value_format: ValueFormat,
+        header: Option<&str>,
+    ) -> std::io::Result<Self> {
+        let mut writer = CountingWriter {
+            inner: writer,
+            count: 0,
+        };
+        if let Some(header) = header.filter(|header| !header.is_empty()) {
+            writer.write_all(header.as_bytes())?;
+            if !header.ends_with('\n') {
+                writer.write_all(b"\n")?;
+            }
+        }
+        Ok(BedGraphWriter {
+            writer,
+            value_format,
+            bed_columns: false,
+            pretty: false,
+            index: OutputIndex::default(),
+        })
+    }
+
+    /// Flushes any buffered output and hands back the underlying writer
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer.inner)
+    }
+
+    /// Writes each record as BED, with the value in place of the score and the other
+    /// columns of the record kept, instead of as bedGraph
+    pub fn set_bed_columns(&mut self, bed_columns: bool) {
+        self.bed_columns = bed_columns;
+    }
+
+    /// Pads the columns before the value with spaces to fixed widths so that they line
+    /// up in a terminal. The tabs stay, but strict bedGraph readers reject the padding
+    pub fn set_pretty(&mut self, pretty: bool) {
+        self.pretty = pretty;
+    }
+
+    /// Where each chromosome's records are in what has been written so far
+    pub fn index(&self) -> &OutputIndex {
+        &self.index
+    }
+
+    pub fn write(&mut self, record: &BedGraphRecord) -> std::io::Result<()> {
+        let start_offset = self.writer.count;
+        let width = match self.pretty {
+            true => PRETTY_WIDTH,
+            false => 0,
+        };
+        write!(
+            self.writer,
+            "{:<width$}\t{:>width$}\t{:>width$}\t",
+            record.chrom, record.start, record.end
+        )?;
+        let columns = record.columns.as_deref().filter(|_| self.bed_columns);
+        if self.bed_columns {
+            let name = columns.map_or("", |columns| columns.name.as_str());
+            let name = if name.is_empty() { "." } else { name };
+            write!(self.writer, "{:<width$}\t", name)?;
+        }
+        self.value_format.write(&mut self.writer, record.value)?;
+        for value in columns.map_or(&[][..], |columns| &columns.values) {
+            write!(self.writer, "\t{}", value)?;
+        }
+        self.writer.write_all(b"\n")?;
+        self.index
+            .add(&record.chrom, start_offset, self.writer.count);
+        Ok(())
+    }
+}
+
+impl<W: Write> RecordWriter for BedGraphWriter<W> {
+    fn write(&mut self, record: &BedGraphRecord) -> std::io::Result<()> {
+        BedGraphWriter::write(self, record)
+    }
+
+    fn write_comment(&mut self, comment: &str) -> std::io::Result<()> {
+        self.writer.write_all(comment.as_bytes())?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(value_format: ValueFormat, value: f64) -> String {
+        let mut output = Vec::new();
+        value_format.write(&mut output, value).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn lossless_values_read_back_bit_for_bit() {
+        for value in [
+            0.1 + 0.2,
+            1e-300,
+            -0.0,
+            f64::MAX,
+            f64::NAN,
+            f64::NEG_INFINITY,
+        ] {
+            let written = format(ValueFormat::Lossless, value);
+            let read: f64 = written.parse().unwrap();
+            assert_eq!(
+                read.to_bits(),
+                value.to_bits(),
+                "{} became {}",
+                value,
+                written
+            );
+        }
+    }
+
+    #[test]
+    fn lossless_values_use_the_shorter_notation() {
+        assert_eq!(format(ValueFormat::Lossless, 1e-300), "1e-300");
+        assert_eq!(format(ValueFormat::Lossless, 0.5), "0.5");
+    }
+}
@@ -0,0 +1,636 @@
+//! A small bigWig writer for sorted bedGraph records.
+//!
+//! Records are written out in blocks of up to [`ITEMS_PER_SLOT`] as they arrive, so only
+//! one block and the index entry of every written block are held in memory. The file has
+//! no zoom levels and its blocks are not compressed, which every bigWig reader supports
+//! but makes the file larger than one written by `bedGraphToBigWig`.
+
+use crate::{bases, BedGraphRecord, ChromSizes, Coord, RecordWriter};
+use std::collections::HashMap;
+use std::io::{self, Seek, SeekFrom, Write};
+
+const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+const CHROM_TREE_MAGIC: u32 = 0x78CA_8C91;
+const INDEX_MAGIC: u32 = 0x2468_ACE0;
+const VERSION: u16 = 4;
+const HEADER_SIZE: u64 = 64;
+const SUMMARY_SIZE: u64 = 40;
+/// The data section starts with the number of blocks, right after the header and summary
+const DATA_OFFSET: u64 = HEADER_SIZE + SUMMARY_SIZE;
+const BLOCK_SIZE: usize = 256;
+const ITEMS_PER_SLOT: usize = 1024;
+const BEDGRAPH_SECTION: u8 = 1;
+
+/// A coordinate in the 32 bits of bigWig, or `None` when it does not fit
+// Checking does nothing to the default 32 bit coordinates
+#[cfg_attr(not(feature = "u64-coords"), allow(clippy::useless_conversion))]
+fn narrow(coord: Coord) -> Option<u32> {
+    u32::try_from(coord).ok()
+}
+
+/// The position and bounds of one block of records, which make up the leaves of the index
+struct Section {
+    chrom_id: u32,
+    start: u32,
+    end: u32,
+    offset: u64,
+    size: u64,
+}
+
+struct Summary {
+    bases_covered: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    sum_squares: f64,
+}
+
+/// Writes bedGraph records as a bigWig file. Records must be grouped by chromosome,
+/// sorted by start and non-overlapping, and every chromosome must be in the chrom sizes
+pub struct BigWigWriter<W: Write + Seek> {
+    writer: W,
+    chrom_sizes: ChromSizes,
+    /// Chromosome ids, handed out in the order chromosomes first appear
+    chrom_ids: HashMap<String, u32>,
+    current_chrom: Option<String>,
+    items: Vec<(u32, u32, f32)>,
+    last_end: u32,
+    sections: Vec<Section>,
+    summary: Summary,
+    offset: u64,
+    closed: bool,
+}
+
+impl<W: Write + Seek> BigWigWriter<W> {
+    /// Reserves space for the header, which is filled in when the writer is closed. Every
+    /// chromosome has to fit in the 32 bit coordinates of bigWig
+    pub fn new(writer: W, chrom_sizes: ChromSizes) -> io::Result<Self> {
+        if let Some((name, size)) = chrom_sizes.iter().find(|&(_, size)| narrow(size).is_none()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Chromosome {} is {} bases long, which is more than bigWig allows",
+                    name, size
+                ),
+            ));
+        }
+        let mut writer = writer;
+        writer.seek(SeekFrom::Start(0))?;
+        writer.write_all(&[0; (DATA_OFFSET + 8) as usize])?;
+        Ok(BigWigWriter {
+            writer,
+            chrom_sizes,
+            chrom_ids: HashMap::new(),
+            current_chrom: None,
+            items: Vec::with_capacity(ITEMS_PER_SLOT),
+            last_end: 0,
+            sections: Vec::new(),
+            summary: Summary {
+                bases_covered: 0,
+                min: f64::INFINITY,
+                max: f64::NEG_INFINITY,
+                sum: 0.0,
+                sum_squares: 0.0,
+            },
+            offset: DATA_OFFSET + 8,
+            closed: false,
+        })
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn flush_section(&mut self) -> io::Result<()> {
+        let (Some(&(start, _, _)), Some(&(_, end, _))) = (self.items.first(), self.items.last())
+        else {
+            return Ok(());
+        };
+        let chrom_id = self.chrom_ids[self.current_chrom.as_deref().unwrap_or_default()];
+        let mut bytes = Vec::with_capacity(24 + self.items.len() * 12);
+        bytes.extend(chrom_id.to_le_bytes());
+        bytes.extend(start.to_le_bytes());
+        bytes.extend(end.to_le_bytes());
+        bytes.extend(0u32.to_le_bytes()); // item step
+        bytes.extend(0u32.to_le_bytes()); // item span
+        bytes.push(BEDGRAPH_SECTION);
+        bytes.push(0);
+        bytes.extend((self.items.len() as u16).to_le_bytes());
+        for (start, end, value) in self.items.drain(..) {
+            bytes.extend(start.to_le_bytes());
+            bytes.extend(end.to_le_bytes());
+            bytes.extend(value.to_le_bytes());
+        }
+        self.sections.push(Section {
+            chrom_id,
+            start,
+            end,
+            offset: self.offset,
+            size: bytes.len() as u64,
+        });
+        self.write_bytes(&bytes)
+    }
+
+    fn chrom_tree(&self) -> Vec<u8> {
+        let mut chroms: Vec<(&str, u32, u32)> = self
+            .chrom_sizes
+            .iter()
+            .map(|(name, size)| {
+                (
+                    name,
+                    self.chrom_ids.get(name).copied(),
+                    narrow(size).expect("sizes are checked in new"),
+                )
+            })
+            .scan(self.chrom_ids.len() as u32, |next_id, (name, id, size)| {
+                // Chromosomes without records get the ids after the ones that do
+                let id = id.unwrap_or_else(|| {
+                    *next_id += 1;
+                    *next_id - 1
+                });
+                Some((name, id, size))
+            })
+            .collect();
+        chroms.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+
+        let key_size = chroms.iter().map(|c| c.0.len()).max().unwrap_or(0).max(1);
+        let block_size = BLOCK_SIZE.min(chroms.len()).max(1);
+        let key = |name: &str| {
+            let mut key = name.as_bytes().to_vec();
+            key.resize(key_size, 0);
+            key
+        };
+
+        let mut bytes = Vec::new();
+        bytes.extend(CHROM_TREE_MAGIC.to_le_bytes());
+        bytes.extend((block_size as u32).to_le_bytes());
+        bytes.extend((key_size as u32).to_le_bytes());
+        bytes.extend(8u32.to_le_bytes()); // chrom id and size
+        bytes.extend((chroms.len() as u64).to_le_bytes());
+        bytes.extend(0u64.to_le_bytes());
+
+        // Leaf and inner items are both a key followed by 8 bytes
+        let tree_start = self.offset + bytes.len() as u64;
+        let levels = TreeLevels::new(chroms.len(), block_size, key_size + 8, tree_start);
+        for level in (0..levels.counts.len()).rev() {
+            for range in levels.nodes(level) {
+                bytes.push((level == 0) as u8);
+                bytes.push(0);
+                bytes.extend((range.len() as u16).to_le_bytes());
+                for item in range {
+                    if level == 0 {
+                        let (name, id, size) = chroms[item];
+                        bytes.extend(key(name));
+                        bytes.extend(id.to_le_bytes());
+                        bytes.extend(size.to_le_bytes());
+                    } else {
+                        bytes.extend(key(chroms[levels.first_leaf(level - 1, item)].0));
+                        bytes.extend(levels.node_offset(level - 1, item).to_le_bytes());
+                    }
+                }
+            }
+        }
+        bytes
+    }
+
+    fn index(&self) -> Vec<u8> {
+        let sections = &self.sections;
+        let bounds = |range: std::ops::Range<usize>| match range.is_empty() {
+            true => (0, 0, 0, 0),
+            false => {
+                let (first, last) = (&sections[range.start], &sections[range.end - 1]);
+                (first.chrom_id, first.start, last.chrom_id, last.end)
+            }
+        };
+
+        let mut bytes = Vec::new();
+        let (start_chrom, start, end_chrom, end) = bounds(0..sections.len());
+        bytes.extend(INDEX_MAGIC.to_le_bytes());
+        bytes.extend((BLOCK_SIZE as u32).to_le_bytes());
+        bytes.extend((sections.len() as u64).to_le_bytes());
+        bytes.extend(start_chrom.to_le_bytes());
+        bytes.extend(start.to_le_bytes());
+        bytes.extend(end_chrom.to_le_bytes());
+        bytes.extend(end.to_le_bytes());
+        bytes.extend(self.offset.to_le_bytes()); // the data section ends where the index starts
+        bytes.extend((ITEMS_PER_SLOT as u32).to_le_bytes());
+        bytes.extend(0u32.to_le_bytes());
+
+        let tree_start = self.offset + bytes.len() as u64;
+        let leaf_levels = TreeLevels::new(sections.len(), BLOCK_SIZE, 32, tree_start);
+        // Inner items lack the data size, so they are 8 bytes shorter than leaf items
+        let levels = TreeLevels {
+            inner_item_size: 24,
+            ..leaf_levels
+        };
+        for level in (0..levels.counts.len()).rev() {
+            for range in levels.nodes(level) {
+                bytes.push((level == 0) as u8);
+                bytes.push(0);
+                bytes.extend((range.len() as u16).to_le_bytes());
+                for item in range {
+                    let covered = match level {
+                        0 => item..item + 1,
+                        _ => levels.leaves(level - 1, item),
+                    };
+                    let (start_chrom, start, end_chrom, end) = bounds(covered);
+                    bytes.extend(start_chrom.to_le_bytes());
+                    bytes.extend(start.to_le_bytes());
+                    bytes.extend(end_chrom.to_le_bytes());
+                    bytes.extend(end.to_le_bytes());
+                    if level == 0 {
+                        bytes.extend(sections[item].offset.to_le_bytes());
+                        bytes.extend(sections[item].size.to_le_bytes());
+                    } else {
+                        bytes.extend(levels.node_offset(level - 1, item).to_le_bytes());
+                    }
+                }
+            }
+        }
+        bytes
+    }
+
+    fn header(&self, chrom_tree_offset: u64, index_offset: u64) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity((DATA_OFFSET + 8) as usize);
+        bytes.extend(BIGWIG_MAGIC.to_le_bytes());
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend(0u16.to_le_bytes()); // zoom levels
+        bytes.extend(chrom_tree_offset.to_le_bytes());
+        bytes.extend(DATA_OFFSET.to_le_bytes());
+        bytes.extend(index_offset.to_le_bytes());
+        bytes.extend(0u16.to_le_bytes()); // field count
+        bytes.extend(0u16.to_le_bytes()); // defined field count
+        bytes.extend(0u64.to_le_bytes()); // autoSql offset
+        bytes.extend(HEADER_SIZE.to_le_bytes()); // total summary offset
+        bytes.extend(0u32.to_le_bytes()); // uncompressed blocks
+        bytes.extend(0u64.to_le_bytes()); // extension offset
+
+        let summary = &self.summary;
+        let covered = summary.bases_covered > 0;
+        bytes.extend(summary.bases_covered.to_le_bytes());
+        bytes.extend(if covered { summary.min } else { 0.0 }.to_le_bytes());
+        bytes.extend(if covered { summary.max } else { 0.0 }.to_le_bytes());
+        bytes.extend(summary.sum.to_le_bytes());
+        bytes.extend(summary.sum_squares.to_le_bytes());
+
+        bytes.extend((self.sections.len() as u64).to_le_bytes());
+        bytes
+    }
+}
+
+impl<W: Write + Seek> RecordWriter for BigWigWriter<W> {
+    fn write(&mut self, record: &BedGraphRecord) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let size = self.chrom_sizes.get(&record.chrom).ok_or_else(|| {
+            invalid(format!(
+                "Chromosome {} is not in the chrom sizes",
+                record.chrom
+            ))
+        })?;
+        if record.end > size {
+            return Err(invalid(format!(
+                "Record {}:{}-{} ends past the chromosome length {}",
+                record.chrom, record.start, record.end, size
+            )));
+        }
+        if self.current_chrom.as_deref() != Some(record.chrom.as_str()) {
+            if self.chrom_ids.contains_key(&record.chrom) {
+                return Err(invalid(format!(
+                    "bigWig output must be sorted, but the records of {} are split into several blocks",
+                    record.chrom
+                )));
+            }
+            self.flush_section()?;
+            self.chrom_ids
+                .insert(record.chrom.clone(), self.chrom_ids.len() as u32);
+            self.current_chrom = Some(record.chrom.clone());
+            self.last_end = 0;
+        }
+        // The record ends within its chromosome, so its coordinates fit in 32 bits
+        let fits = "the chromosome fits in 32 bits";
+        let (start, end) = (
+            narrow(record.start).expect(fits),
+            narrow(record.end).expect(fits),
+        );
+        if start < self.last_end {
+            return Err(invalid(format!(
+                "bigWig output must be sorted and non-overlapping, but {}:{}-{} starts before the previous record ends at {}",
+                record.chrom, record.start, record.end, self.last_end
+            )));
+        }
+        self.last_end = end;
+
+        let bases = bases(record.start, record.end);
+        let summary = &mut self.summary;
+        summary.bases_covered += bases;
+        summary.min = summary.min.min(record.value);
+        summary.max = summary.max.max(record.value);
+        summary.sum += record.value * bases as f64;
+        summary.sum_squares += record.value * record.value * bases as f64;
+
+        self.items.push((start, end, record.value as f32));
+        if self.items.len() == ITEMS_PER_SLOT {
+            self.flush_section()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the chromosome tree, the index and the header
+    fn close(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        self.flush_section()?;
+
+        let chrom_tree_offset = self.offset;
+        let chrom_tree = self.chrom_tree();
+        self.write_bytes(&chrom_tree)?;
+
+        let index_offset = self.offset;
+        let index = self.index();
+        self.write_bytes(&index)?;
+        self.write_bytes(&BIGWIG_MAGIC.to_le_bytes())?;
+
+        let header = self.header(chrom_tree_offset, index_offset);
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header)?;
+        self.writer.flush()
+    }
+}
+
+/// The layout of a tree whose leaves hold `item_count` items and whose nodes hold up to
+/// `block_size` children each, written root first with every level after the one above
+#[derive(Clone)]
+struct TreeLevels {
+    /// The number of nodes in each level, leaves first
+    counts: Vec<usize>,
+    item_count: usize,
+    block_size: usize,
+    leaf_item_size: usize,
+    inner_item_size: usize,
+    tree_start: u64,
+}
+
+impl TreeLevels {
+    fn new(item_count: usize, block_size: usize, item_size: usize, tree_start: u64) -> Self {
+        let mut counts = Vec::new();
+        let mut items = item_count;
+        loop {
+            let nodes = items.div_ceil(block_size).max(1);
+            counts.push(nodes);
+            if nodes == 1 {
+                break;
+            }
+            items = nodes;
+        }
+        TreeLevels {
+            counts,
+            item_count,
+            block_size,
+            leaf_item_size: item_size,
+            inner_item_size: item_size,
+            tree_start,
+        }
+    }
+
+    /// The number of items held by the nodes of a level
+    fn items(&self, level: usize) -> usize {
+        match level {
+            0 => self.item_count,
+            _ => self.counts[level - 1],
+        }
+    }
+
+    /// The items held by each node of a level
+    fn nodes(&self, level: usize) -> impl Iterator<Item = std::ops::Range<usize>> {
+        let (items, block_size) = (self.items(level), self.block_size);
+        (0..self.counts[level])
+            .map(move |node| node * block_size..((node + 1) * block_size).min(items))
+    }
+
+    fn node_size(&self, level: usize, items: usize) -> u64 {
+        let item_size = match level {
+            0 => self.leaf_item_size,
+            _ => self.inner_item_size,
+        };
+        (4 + items * item_size) as u64
+    }
+
+    /// The file offset of a node, given that all nodes but the last of a level are full
+    fn node_offset(&self, level: usize, node: usize) -> u64 {
+        let levels_above: u64 = (level + 1..self.counts.len())
+            .map(|above| {
+                self.nodes(above)
+                    .map(|range| self.node_size(above, range.len()))
+                    .sum::<u64>()
+            })
+            .sum();
+        self.tree_start + levels_above + node as u64 * self.node_size(level, self.block_size)
+    }
+
+    /// The leaf items under a node
+    fn leaves(&self, level: usize, node: usize) -> std::ops::Range<usize> {
+        let span = self.block_size.pow(level as u32 + 1);
+        node * span..((node + 1) * span).min(self.item_count)
+    }
+
+    /// The first leaf item under a node
+    fn first_leaf(&self, level: usize, node: usize) -> usize {
+        self.leaves(level, node).start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::record;
+    use std::io::Cursor;
+
+    /// A chromosome tree leaf: the name, id and size
+    type Chrom = (String, u32, u32);
+    /// The chromosome id of a block and its start, end and value items
+    type Block = (u32, Vec<(u32, u32, f32)>);
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+    }
+
+    /// Writes `a:0-10`, `a:20-30` and one record on each of `chroms` more chromosomes
+    /// named `c0`, `c1` and so on, with `a` and `unused` left in the sizes
+    fn write(chroms: usize) -> Vec<u8> {
+        let mut sizes = ChromSizes::default();
+        sizes.insert("unused".to_string(), 50);
+        sizes.insert("a".to_string(), 100);
+        let names: Vec<String> = (0..chroms).map(|i| format!("c{}", i)).collect();
+        for name in &names {
+            sizes.insert(name.clone(), 10);
+        }
+        let mut writer = BigWigWriter::new(Cursor::new(Vec::new()), sizes).unwrap();
+        writer.write(&record("a", 0, 10, 1.0)).unwrap();
+        writer.write(&record("a", 20, 30, 3.0)).unwrap();
+        for name in &names {
+            writer.write(&record(name, 0, 10, 2.0)).unwrap();
+        }
+        writer.close().unwrap();
+        writer.writer.into_inner()
+    }
+
+    /// The leaf items of the B+ tree at `node`, checking that each inner item is keyed by
+    /// the first leaf under it
+    fn chrom_leaves(bytes: &[u8], node: usize, key_size: usize, out: &mut Vec<Chrom>) {
+        let (leaf, count) = (bytes[node] == 1, u16_at(bytes, node + 2) as usize);
+        for item in 0..count {
+            let at = node + 4 + item * (key_size + 8);
+            let key = &bytes[at..at + key_size];
+            if leaf {
+                let name = String::from_utf8(key.iter().copied().take_while(|&b| b != 0).collect());
+                out.push((
+                    name.unwrap(),
+                    u32_at(bytes, at + key_size),
+                    u32_at(bytes, at + key_size + 4),
+                ));
+            } else {
+                let first = out.len();
+                chrom_leaves(bytes, u64_at(bytes, at + key_size) as usize, key_size, out);
+                let mut first_key = out[first].0.as_bytes().to_vec();
+                first_key.resize(key_size, 0);
+                assert_eq!(key, first_key);
+            }
+        }
+    }
+
+    /// The leaf items of the R tree at `node`, as bounds, offset and size, checking that
+    /// each inner item's bounds are those of the leaves under it
+    fn index_leaves(bytes: &[u8], node: usize, out: &mut Vec<([u32; 4], u64, u64)>) {
+        let (leaf, count) = (bytes[node] == 1, u16_at(bytes, node + 2) as usize);
+        let item_size = if leaf { 32 } else { 24 };
+        for item in 0..count {
+            let at = node + 4 + item * item_size;
+            let bounds = [0, 4, 8, 12].map(|field| u32_at(bytes, at + field));
+            if leaf {
+                out.push((bounds, u64_at(bytes, at + 16), u64_at(bytes, at + 24)));
+            } else {
+                let first = out.len();
+                index_leaves(bytes, u64_at(bytes, at + 16) as usize, out);
+                let (start, end) = (out[first].0, out[out.len() - 1].0);
+                assert_eq!(bounds, [start[0], start[1], end[2], end[3]]);
+            }
+        }
+    }
+
+    /// Reads the file back through the header, chromosome tree and index, checking the
+    /// structure on the way, and gives the chromosomes by name and every block's records
+    fn read(bytes: &[u8]) -> (Vec<Chrom>, Vec<Block>) {
+        assert_eq!(u32_at(bytes, 0), BIGWIG_MAGIC);
+        assert_eq!(u16_at(bytes, 4), VERSION);
+        assert_eq!(u16_at(bytes, 6), 0);
+        let chrom_tree = u64_at(bytes, 8) as usize;
+        let data = u64_at(bytes, 16) as usize;
+        let index = u64_at(bytes, 24) as usize;
+        assert_eq!(u64_at(bytes, 44), HEADER_SIZE);
+        assert_eq!(&bytes[bytes.len() - 4..], BIGWIG_MAGIC.to_le_bytes());
+
+        assert_eq!(u32_at(bytes, chrom_tree), CHROM_TREE_MAGIC);
+        let key_size = u32_at(bytes, chrom_tree + 8) as usize;
+        assert_eq!(u32_at(bytes, chrom_tree + 12), 8);
+        let chrom_count = u64_at(bytes, chrom_tree + 16) as usize;
+        let mut chroms = Vec::new();
+        chrom_leaves(bytes, chrom_tree + 32, key_size, &mut chroms);
+        assert_eq!(chroms.len(), chrom_count);
+        assert!(chroms.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        assert_eq!(u32_at(bytes, index), INDEX_MAGIC);
+        let section_count = u64_at(bytes, index + 8) as usize;
+        assert_eq!(u64_at(bytes, data), section_count as u64);
+        assert_eq!(
+            u64_at(bytes, HEADER_SIZE as usize + 40),
+            section_count as u64
+        );
+        let mut sections = Vec::new();
+        index_leaves(bytes, index + 48, &mut sections);
+        assert_eq!(sections.len(), section_count);
+
+        let blocks = sections
+            .iter()
+            .map(|&(bounds, offset, size)| {
+                let at = offset as usize;
+                let count = u16_at(bytes, at + 22) as usize;
+                assert_eq!(size as usize, 24 + count * 12);
+                assert_eq!(bytes[at + 20], BEDGRAPH_SECTION);
+                assert_eq!(
+                    [0, 4].map(|field| u32_at(bytes, at + field)),
+                    [bounds[0], bounds[1]]
+                );
+                assert_eq!(u32_at(bytes, at + 8), bounds[3]);
+                let items = (0..count)
+                    .map(|item| {
+                        let at = at + 24 + item * 12;
+                        let value = f32::from_le_bytes(bytes[at + 8..at + 12].try_into().unwrap());
+                        (u32_at(bytes, at), u32_at(bytes, at + 4), value)
+                    })
+                    .collect();
+                (bounds[0], items)
+            })
+            .collect();
+        (chroms, blocks)
+    }
+
+    #[test]
+    fn reads_back_through_the_header_chrom_tree_and_index() {
+        let bytes = write(1);
+        let (chroms, blocks) = read(&bytes);
+        let chroms: Vec<_> = chroms
+            .iter()
+            .map(|(name, id, size)| (name.as_str(), *id, *size))
+            .collect();
+        // Ids follow the order of the records, then the chromosomes without any
+        assert_eq!(
+            chroms,
+            vec![("a", 0, 100), ("c0", 1, 10), ("unused", 2, 50)]
+        );
+        assert_eq!(
+            blocks,
+            vec![
+                (0, vec![(0, 10, 1.0), (20, 30, 3.0)]),
+                (1, vec![(0, 10, 2.0)]),
+            ]
+        );
+        // The summary covers every base that was written
+        let summary = HEADER_SIZE as usize;
+        assert_eq!(u64_at(&bytes, summary), 30);
+        assert_eq!(
+            f64::from_le_bytes(bytes[summary + 24..summary + 32].try_into().unwrap()),
+            60.0
+        );
+    }
+
+    #[test]
+    fn trees_grow_inner_levels_past_one_block() {
+        let (chroms, blocks) = read(&write(300));
+        assert_eq!(chroms.len(), 302);
+        assert_eq!(blocks.len(), 301);
+        let ids: Vec<u32> = blocks.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, (0..301).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unsorted_records_are_invalid_data() {
+        let mut sizes = ChromSizes::default();
+        sizes.insert("a".to_string(), 100);
+        let mut writer = BigWigWriter::new(Cursor::new(Vec::new()), sizes).unwrap();
+        writer.write(&record("a", 20, 30, 1.0)).unwrap();
+        let error = writer.write(&record("a", 15, 25, 1.0)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::{bases, BedGraphRecord, ChromSizes, Coord, Error, Stage};
+use clap::ValueEnum;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinStat {
+    /// The mean of the values overlapping the bin, weighted by how many bases of the bin
+    /// each one covers
+    Mean,
+    /// The sum of each value times the number of bases of the bin it covers
+    Sum,
+    /// The largest value overlapping the bin
+    Max,
+    /// The smallest value overlapping the bin
+    Min,
+}
+
+#[derive(Clone, Debug)]
+pub struct BinOptions {
+    /// The width of each bin. Bins start at 0 on every chromosome
+    pub size: Coord,
+    /// The value reported for each bin
+    pub stat: BinStat,
+    /// Write every bin of every chromosome in these sizes instead of only the occupied
+    /// ones, in the order of the sizes. Empty bins are 0, the last bin of a chromosome
+    /// stops at its end, and bases no record covers count as 0 for the mean
+    pub tiles: Option<ChromSizes>,
+}
+
+struct Bin {
+    weighted_sum: f64,
+    bases: u64,
+    max: f64,
+    min: f64,
+}
+
+/// Summarises the values in fixed-size bins, writing one record for every bin that
+/// overlaps at least one record. All bins are held until the input ends so the input
+/// does not have to be sorted, unless `streaming` is set for sorted input, when each bin
+/// is passed on as soon as a record starts past it
+pub(crate) struct Binner {
+    options: BinOptions,
+    streaming: bool,
+    /// Chromosomes in the order they were first seen
+    chroms: HashMap<String, usize>,
+    chrom_names: Vec<String>,
+    bins: BTreeMap<(usize, Coord), Bin>,
+}
+
+impl Binner {
+    pub(crate) fn new(options: BinOptions, streaming: bool) -> Self {
+        Binner {
+            // Tiles come out in the order of the chrom sizes, which sorted input need
+            // not follow
+            streaming: streaming && options.tiles.is_none(),
+            options,
+            chroms: HashMap::new(),
+            chrom_names: Vec::new(),
+            bins: BTreeMap::new(),
+        }
+    }
+
+    /// Reports a bin that ends at `end`. Tiles are averaged over all of their bases, bins
+    /// only over the ones a record covers
+    fn bin_record(
+        &self,
+        chrom: String,
+        bin: Coord,
+        end: Coord,
+        summary: Bin,
+        tile: bool,
+    ) -> BedGraphRecord {
+        let start = bin * self.options.size;
+        let bases = match tile {
+            true => bases(start, end),
+            false => summary.bases,
+        };
+        let value = match self.options.stat {
+            BinStat::Mean => summary.weighted_sum / bases as f64,
+            BinStat::Sum => summary.weighted_sum,
+            BinStat::Max => summary.max,
+            BinStat::Min => summary.min,
+        };
+        BedGraphRecord {
+            chrom,
+            start,
+            end,
+            value,
+            columns: None,
+        }
+    }
+
+    /// Passes on the bins that come before `bin` of `chrom`
+    fn finish_before(&mut self, chrom: usize, bin: Coord, out: &mut Vec<BedGraphRecord>) {
+        let rest = self.bins.split_off(&(chrom, bin));
+        let size = self.options.size;
+        for ((chrom, bin), summary) in std::mem::replace(&mut self.bins, rest) {
+            let end = (bin * size).saturating_add(size);
+            out.push(self.bin_record(self.chrom_names[chrom].clone(), bin, end, summary, false));
+        }
+    }
+
+    /// Writes every tile of the chromosomes in `sizes`, including the empty ones
+    fn finish_tiles(&mut self, sizes: &ChromSizes, out: &mut Vec<BedGraphRecord>) {
+        let size = self.options.size;
+        for (name, length) in sizes.iter() {
+            let chrom = self.chroms.get(name).copied();
+            for bin in 0..length.div_ceil(size) {
+                let summary = chrom.and_then(|chrom| self.bins.remove(&(chrom, bin)));
+                let end = (bin * size).saturating_add(size).min(length);
+                out.push(match summary {
+                    Some(summary) => self.bin_record(name.to_string(), bin, end, summary, true),
+                    None => BedGraphRecord {
+                        chrom: name.to_string(),
+                        start: bin * size,
+                        end,
+                        value: 0.0,
+                        columns: None,
+                    },
+                });
+            }
+        }
+    }
+}
+
+impl Stage for Binner {
+    fn push(&mut self, record: BedGraphRecord, out: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        let mut record = record;
+        if let Some(sizes) = &self.options.tiles {
+            // The converter rejects chromosomes missing from the sizes before they get here
+            if let Some(length) = sizes.get(&record.chrom) {
+                record.end = record.end.min(length);
+            }
+        }
+        if record.end <= record.start {
+            return Ok(());
+        }
+        let chrom = *self.chroms.entry(record.chrom.clone()).or_insert_with(|| {
+            self.chrom_names.push(record.chrom.clone());
+            self.chrom_names.len() - 1
+        });
+        let size = self.options.size;
+        if self.streaming {
+            // Chromosomes are numbered as they are first seen, so on sorted input every
+            // earlier chromosome is done too
+            self.finish_before(chrom, record.start / size, out);
+        }
+        for bin in record.start / size..=(record.end - 1) / size {
+            let bin_start = bin * size;
+            let bin_end = bin_start.saturating_add(size);
+            let overlap = bases(record.start.max(bin_start), record.end.min(bin_end));
+            let summary = self.bins.entry((chrom, bin)).or_insert(Bin {
+                weighted_sum: 0.0,
+                bases: 0,
+                max: f64::NEG_INFINITY,
+                min: f64::INFINITY,
+            });
+            summary.weighted_sum += record.value * overlap as f64;
+            summary.bases += overlap;
+            summary.max = summary.max.max(record.value);
+            summary.min = summary.min.min(record.value);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        if let Some(sizes) = self.options.tiles.take() {
+            self.finish_tiles(&sizes, out);
+            return Ok(());
+        }
+        self.finish_before(usize::MAX, 0, out);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{record, spans};
+
+    #[test]
+    fn the_last_tile_ends_at_the_chromosome_end() {
+        let mut sizes = ChromSizes::default();
+        sizes.insert("a".to_string(), 25);
+        let options = BinOptions {
+            size: 10,
+            stat: BinStat::Mean,
+            tiles: Some(sizes),
+        };
+        let mut binner = Binner::new(options, false);
+        let mut out = Vec::new();
+        binner.push(record("a", 0, 10, 2.0), &mut out).unwrap();
+        binner.push(record("a", 22, 25, 4.0), &mut out).unwrap();
+        binner.finish(&mut out).unwrap();
+        // The last tile is averaged over its 5 bases, not the tile size
+        assert_eq!(
+            spans(&out),
+            vec![(0, 10, 2.0), (10, 20, 0.0), (20, 25, 2.4)]
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use crate::Error;
+use regex::Regex;
+use std::collections::HashSet;
+use std::io::BufRead;
+
+/// Which chromosomes are converted. A chromosome is kept when it is one of the names or
+/// matches any of the patterns, so names and patterns add up rather than narrow down
+#[derive(Clone, Debug, Default)]
+pub struct ChromFilter {
+    names: HashSet<String>,
+    patterns: Vec<Regex>,
+}
+
+impl ChromFilter {
+    pub fn new(names: impl IntoIterator<Item = String>, patterns: Vec<Regex>) -> Self {
+        ChromFilter {
+            names: names.into_iter().collect(),
+            patterns,
+        }
+    }
+
+    /// Adds the names in a file with one chromosome per line, ignoring blank lines
+    pub fn read_names<R: BufRead>(&mut self, reader: R) -> Result<(), Error> {
+        for line in reader.lines() {
+            let line = line.map_err(Error::Read)?;
+            let name = line.trim();
+            if !name.is_empty() {
+                self.names.insert(name.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn keeps(&self, chrom: &str) -> bool {
+        self.names.contains(chrom) || self.patterns.iter().any(|pattern| pattern.is_match(chrom))
+    }
+}
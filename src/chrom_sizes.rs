@@ -0,0 +1,128 @@
+use crate::{Coord, Error};
+use std::collections::HashMap;
+use std::io::BufRead;
+
+/// Chromosome lengths as read from a `chrom.sizes` file or a `.fai` FASTA index, kept in
+/// file order
+#[derive(Clone, Debug, Default)]
+pub struct ChromSizes {
+    sizes: Vec<(String, Coord)>,
+    index: HashMap<String, usize>,
+}
+
+/// The columns of a `.fai` index: name, length, offset, line bases and line width
+const FAI_COLUMNS: usize = 5;
+
+impl ChromSizes {
+    /// Reads `name<TAB>length` lines, ignoring blank lines and any further columns. A file
+    /// whose first line has the five columns of a `.fai` index is read as one, with every
+    /// line checked to be a `.fai` line
+    pub fn read<R: BufRead>(reader: R) -> Result<Self, Error> {
+        let mut chrom_sizes = ChromSizes::default();
+        let mut fai = None;
+        for (index, line) in reader.lines().enumerate() {
+            let line = line.map_err(Error::Read)?;
+            let line_number = index + 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+            let fai = *fai.get_or_insert(fields.len() == FAI_COLUMNS);
+            let fai_line = fields.len() == FAI_COLUMNS
+                && fields[2..].iter().all(|field| field.parse::<u64>().is_ok());
+            let size = fields.get(1).and_then(|size| size.parse::<Coord>().ok());
+            let size = match (fai, size) {
+                (true, Some(size)) if fai_line => size,
+                (true, _) => {
+                    return Err(Error::parse(
+                        line_number,
+                        "Expected `name<TAB>length<TAB>offset<TAB>linebases<TAB>linewidth` in .fai index",
+                    ))
+                }
+                (false, Some(size)) => size,
+                (false, None) => {
+                    return Err(Error::parse(
+                        line_number,
+                        "Expected `name<TAB>length` in chrom sizes",
+                    ))
+                }
+            };
+            chrom_sizes.insert(fields[0].to_string(), size);
+        }
+        Ok(chrom_sizes)
+    }
+
+    /// Adds a chromosome, replacing the length of one that is already known
+    pub fn insert(&mut self, name: String, size: Coord) {
+        match self.index.get(&name) {
+            Some(&index) => self.sizes[index].1 = size,
+            None => {
+                self.index.insert(name.clone(), self.sizes.len());
+                self.sizes.push((name, size));
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Coord> {
+        self.index.get(name).map(|&index| self.sizes[index].1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sizes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    /// The chromosomes and their lengths in the order they were read
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Coord)> {
+        self.sizes.iter().map(|(name, size)| (name.as_str(), *size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(text: &str) -> Result<Vec<(String, Coord)>, Error> {
+        let sizes = ChromSizes::read(text.as_bytes())?;
+        Ok(sizes
+            .iter()
+            .map(|(name, size)| (name.to_string(), size))
+            .collect())
+    }
+
+    #[test]
+    fn two_columns_are_read_in_order() {
+        let sizes = read("chr2\t200\n\nchr1\t100\n").unwrap();
+        assert_eq!(
+            sizes,
+            vec![("chr2".to_string(), 200), ("chr1".to_string(), 100)]
+        );
+    }
+
+    #[test]
+    fn fai_indexes_are_read() {
+        let sizes = read("chr1\t100\t6\t60\t61\nchr2\t200\t114\t60\t61\n").unwrap();
+        assert_eq!(
+            sizes,
+            vec![("chr1".to_string(), 100), ("chr2".to_string(), 200)]
+        );
+    }
+
+    #[test]
+    fn fai_indexes_reject_other_lines() {
+        let error = read("chr1\t100\t6\t60\t61\nchr2\t200\n").unwrap_err();
+        assert!(matches!(error, Error::Parse { line: 2, .. }));
+    }
+
+    #[test]
+    fn further_columns_are_ignored_outside_fai_indexes() {
+        let sizes = read("chr1\t100\tx\nchr2\t200\tx\ty\n").unwrap();
+        assert_eq!(
+            sizes,
+            vec![("chr1".to_string(), 100), ("chr2".to_string(), 200)]
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use crate::{BedGraphRecord, Coord};
+use clap::ValueEnum;
+use std::collections::HashMap;
+
+/// What makes two records duplicates of each other
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DedupBy {
+    /// The same chromosome, start and end
+    Coord,
+}
+
+/// Which value a set of duplicates is collapsed to
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DedupKeep {
+    #[default]
+    Max,
+    Min,
+    Mean,
+    /// The value of the first of the duplicates
+    First,
+}
+
+#[derive(Clone, Debug)]
+pub struct DedupOptions {
+    pub by: DedupBy,
+    pub keep: DedupKeep,
+}
+
+struct Group {
+    record: BedGraphRecord,
+    sum: f64,
+    count: u64,
+}
+
+/// Collapses records with the same coordinates into one, passing them on in the order
+/// each was first seen. Everything is held until the input ends unless `streaming` is set
+/// for sorted input, when a group is passed on once a record starts past it
+pub(crate) struct Deduplicator {
+    keep: DedupKeep,
+    streaming: bool,
+    groups: Vec<Group>,
+    index: HashMap<(String, Coord, Coord), usize>,
+    /// The number of records that were folded into an earlier one
+    pub(crate) collapsed: u64,
+}
+
+impl Deduplicator {
+    pub(crate) fn new(options: &DedupOptions, streaming: bool) -> Self {
+        match options.by {
+            DedupBy::Coord => Deduplicator {
+                keep: options.keep,
+                streaming,
+                groups: Vec::new(),
+                index: HashMap::new(),
+                collapsed: 0,
+            },
+        }
+    }
+
+    pub(crate) fn push(&mut self, record: BedGraphRecord, out: &mut Vec<BedGraphRecord>) {
+        if self.streaming {
+            let passed = |group: &Group| {
+                group.record.chrom != record.chrom || group.record.start < record.start
+            };
+            if self.groups.iter().any(passed) {
+                let groups = std::mem::take(&mut self.groups);
+                for group in groups {
+                    match passed(&group) {
+                        true => out.push(self.finish_group(group)),
+                        false => self.groups.push(group),
+                    }
+                }
+                self.index.clear();
+                for (position, group) in self.groups.iter().enumerate() {
+                    let key = (
+                        group.record.chrom.clone(),
+                        group.record.start,
+                        group.record.end,
+                    );
+                    self.index.insert(key, position);
+                }
+            }
+        }
+
+        let key = (record.chrom.clone(), record.start, record.end);
+        match self.index.get(&key) {
+            Some(&position) => {
+                let group = &mut self.groups[position];
+                group.record.value = match self.keep {
+                    DedupKeep::Max => group.record.value.max(record.value),
+                    DedupKeep::Min => group.record.value.min(record.value),
+                    DedupKeep::Mean | DedupKeep::First => group.record.value,
+                };
+                group.sum += record.value;
+                group.count += 1;
+                self.collapsed += 1;
+            }
+            None => {
+                self.index.insert(key, self.groups.len());
+                self.groups.push(Group {
+                    sum: record.value,
+                    count: 1,
+                    record,
+                });
+            }
+        }
+    }
+
+    fn finish_group(&self, group: Group) -> BedGraphRecord {
+        let mut record = group.record;
+        if self.keep == DedupKeep::Mean {
+            record.value = group.sum / group.count as f64;
+        }
+        record
+    }
+
+    pub(crate) fn finish(&mut self, out: &mut Vec<BedGraphRecord>) {
+        self.index.clear();
+        for group in std::mem::take(&mut self.groups) {
+            out.push(self.finish_group(group));
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+/// How much uncompressed output goes into each gzip member
+const BLOCK_SIZE: usize = 1 << 20;
+
+type Job = (u64, Vec<u8>);
+type Compressed = (u64, io::Result<Vec<u8>>);
+
+fn compress(block: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(block.len() / 4), level);
+    encoder.write_all(block)?;
+    encoder.finish()
+}
+
+/// Gzip-compresses what is written to it on several threads. The output is cut into
+/// blocks that are compressed into separate gzip members and written in order, which
+/// any gzip reader reads back as a single stream. Flushing ends the current member
+pub struct ParallelGzEncoder<W: Write> {
+    writer: W,
+    block: Vec<u8>,
+    /// Until the first member is written, so that even empty output is valid gzip
+    empty: bool,
+    jobs: Option<SyncSender<Job>>,
+    results: Receiver<Compressed>,
+    workers: Vec<JoinHandle<()>>,
+    /// Blocks that came back out of order, waiting for the ones before them
+    done: BTreeMap<u64, Vec<u8>>,
+    next_job: u64,
+    next_write: u64,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    pub fn new(writer: W, level: u32, threads: usize) -> Self {
+        let threads = threads.max(1);
+        let level = Compression::new(level.min(9));
+        let (jobs, queue) = mpsc::sync_channel::<Job>(threads);
+        let (done, results) = mpsc::channel();
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..threads)
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let done = done.clone();
+                std::thread::spawn(move || loop {
+                    let job = queue.lock().expect("queue lock poisoned").recv();
+                    let Ok((index, block)) = job else {
+                        break;
+                    };
+                    if done.send((index, compress(&block, level))).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+        ParallelGzEncoder {
+            writer,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            empty: true,
+            jobs: Some(jobs),
+            results,
+            workers,
+            done: BTreeMap::new(),
+            next_job: 0,
+            next_write: 0,
+        }
+    }
+
+    /// Hands the current block to the workers, first making room by writing out
+    /// finished blocks when too many are in flight
+    fn send_block(&mut self) -> io::Result<()> {
+        let in_flight_limit = 2 * self.workers.len() as u64;
+        while self.next_job - self.next_write >= in_flight_limit {
+            self.write_next()?;
+        }
+        let block = std::mem::replace(&mut self.block, Vec::with_capacity(BLOCK_SIZE));
+        let jobs = self.jobs.as_ref().expect("jobs are only closed on drop");
+        jobs.send((self.next_job, block))
+            .map_err(|_| io::Error::other("a gzip worker stopped"))?;
+        self.next_job += 1;
+        self.empty = false;
+        Ok(())
+    }
+
+    /// Waits for the next block in order and writes it
+    fn write_next(&mut self) -> io::Result<()> {
+        while !self.done.contains_key(&self.next_write) {
+            let (index, compressed) = self
+                .results
+                .recv()
+                .map_err(|_| io::Error::other("a gzip worker stopped"))?;
+            self.done.insert(index, compressed?);
+        }
+        let compressed = self
+            .done
+            .remove(&self.next_write)
+            .expect("the block was just received");
+        self.writer.write_all(&compressed)?;
+        self.next_write += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = BLOCK_SIZE - self.block.len();
+        let taken = buf.len().min(room);
+        self.block.extend_from_slice(&buf[..taken]);
+        if self.block.len() == BLOCK_SIZE {
+            self.send_block()?;
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.block.is_empty() || self.empty {
+            self.send_block()?;
+        }
+        while self.next_write < self.next_job {
+            self.write_next()?;
+        }
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for ParallelGzEncoder<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+        // Closing the queue lets the workers finish
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    fn round_trip(data: &[u8], threads: usize) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        {
+            let mut encoder = ParallelGzEncoder::new(&mut compressed, 6, threads);
+            encoder.write_all(data).unwrap();
+            encoder.flush().unwrap();
+        }
+        // Even empty output has to be a gzip member, or gzip readers reject it
+        assert!(compressed.starts_with(&[0x1f, 0x8b]));
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        decompressed
+    }
+
+    #[test]
+    fn several_blocks_read_back_in_order() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 7 / 2)
+            .flat_map(|index| format!("c\t{}\t{}\t1\n", index, index + 1).into_bytes())
+            .take(BLOCK_SIZE * 7 / 2)
+            .collect();
+        assert_eq!(round_trip(&data, 4), data);
+    }
+
+    #[test]
+    fn empty_output_is_valid_gzip() {
+        assert_eq!(round_trip(b"", 4), b"");
+    }
+}
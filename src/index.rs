@@ -0,0 +1,101 @@
+use crate::Error;
+use std::io::{BufRead, Read, Seek, SeekFrom, Take, Write};
+
+/// A run of consecutive records of one chromosome in a bedGraph file, as byte offsets
+/// from the start of the file. `end_offset` is exclusive
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub chrom: String,
+    pub start_offset: u64,
+    pub end_offset: u64,
+}
+
+/// Where each chromosome's records are in an uncompressed bedGraph file, so a single
+/// chromosome can be read without scanning the whole file. Stored as
+/// `chrom<TAB>start_offset<TAB>end_offset` lines, one per block of records
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutputIndex {
+    entries: Vec<IndexEntry>,
+}
+
+impl OutputIndex {
+    pub fn read<R: BufRead>(reader: R) -> Result<Self, Error> {
+        let mut entries = Vec::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line.map_err(Error::Read)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut fields = line.trim_end().split('\t');
+            let entry = (|| {
+                let chrom = fields.next()?;
+                let start_offset = fields.next()?.parse::<u64>().ok()?;
+                let end_offset = fields.next()?.parse::<u64>().ok()?;
+                (fields.next().is_none() && start_offset <= end_offset).then(|| IndexEntry {
+                    chrom: chrom.to_string(),
+                    start_offset,
+                    end_offset,
+                })
+            })();
+            entries.push(entry.ok_or_else(|| {
+                Error::parse(
+                    index + 1,
+                    "Expected `chrom<TAB>start_offset<TAB>end_offset` in index",
+                )
+            })?);
+        }
+        Ok(OutputIndex { entries })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        for entry in &self.entries {
+            writeln!(
+                writer,
+                "{}\t{}\t{}",
+                entry.chrom, entry.start_offset, entry.end_offset
+            )?;
+        }
+        writer.flush()
+    }
+
+    /// Notes that the bytes from `start_offset` to `end_offset` hold a record of `chrom`,
+    /// growing the last entry when it is the same chromosome
+    pub(crate) fn add(&mut self, chrom: &str, start_offset: u64, end_offset: u64) {
+        match self.entries.last_mut() {
+            Some(last) if last.chrom == chrom && last.end_offset == start_offset => {
+                last.end_offset = end_offset;
+            }
+            _ => self.entries.push(IndexEntry {
+                chrom: chrom.to_string(),
+                start_offset,
+                end_offset,
+            }),
+        }
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// The blocks of a chromosome in file order. There is more than one when the
+    /// chromosome was split across the input
+    pub fn ranges<'a>(&'a self, chrom: &'a str) -> impl Iterator<Item = &'a IndexEntry> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.chrom == chrom)
+    }
+
+    /// Seeks `reader` to the first block of `chrom` and limits it to that block, or
+    /// returns `None` when the chromosome is not in the index
+    pub fn seek_to_chrom<R: Read + Seek>(
+        &self,
+        mut reader: R,
+        chrom: &str,
+    ) -> std::io::Result<Option<Take<R>>> {
+        let Some(entry) = self.ranges(chrom).next() else {
+            return Ok(None);
+        };
+        reader.seek(SeekFrom::Start(entry.start_offset))?;
+        Ok(Some(reader.take(entry.end_offset - entry.start_offset)))
+    }
+}
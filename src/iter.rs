@@ -0,0 +1,139 @@
+use crate::{BedGraphRecord, BedRecord, ConvertOptions, Converter, Error, RecordWriter};
+use std::collections::VecDeque;
+
+/// Holds on to what the converter writes until the iterator hands it out
+#[derive(Default)]
+struct Collector {
+    records: VecDeque<BedGraphRecord>,
+}
+
+impl RecordWriter for Collector {
+    fn write(&mut self, record: &BedGraphRecord) -> std::io::Result<()> {
+        self.records.push_back(record.clone());
+        Ok(())
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct ToBedGraph<'a, I> {
+    records: I,
+    /// Until the input ends or fails
+    converter: Option<Converter<'a, Collector>>,
+    /// What was still held back when the converter finished
+    rest: VecDeque<BedGraphRecord>,
+    record_number: usize,
+}
+
+impl<I: Iterator<Item = BedRecord>> Iterator for ToBedGraph<'_, I> {
+    type Item = Result<BedGraphRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some(converter) = self.converter.as_mut() else {
+                return self.rest.pop_front().map(Ok);
+            };
+            if let Some(record) = converter.writer.records.pop_front() {
+                return Some(Ok(record));
+            }
+            let record = match converter.reached_head() {
+                true => None,
+                false => self.records.next(),
+            };
+            let result = match record {
+                Some(record) => {
+                    self.record_number += 1;
+                    let scale = converter.opts.scale;
+                    converter.add_record(record, self.record_number, scale)
+                }
+                None => {
+                    let converter = self.converter.take().expect("checked above");
+                    converter
+                        .finish_into_writer()
+                        .map(|(_, collector)| self.rest = collector.records)
+                }
+            };
+            if let Err(e) = result {
+                self.converter = None;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Runs records through the conversion without writing them anywhere, yielding the
+/// bedGraph records that would have been written. Options that hold records back, like
+/// `bins` or `zscore`, hold them here too. Errors point at the number of the record in
+/// `records` instead of a line, and end the iteration
+pub fn to_bedgraph<'a, I>(
+    records: I,
+    opts: &'a ConvertOptions,
+) -> impl Iterator<Item = Result<BedGraphRecord, Error>> + 'a
+where
+    I: IntoIterator<Item = BedRecord>,
+    I::IntoIter: 'a,
+{
+    ToBedGraph {
+        records: records.into_iter(),
+        converter: Some(Converter::with_writer(Collector::default(), opts)),
+        rest: VecDeque::new(),
+        record_number: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::bed_record;
+    use crate::{BinOptions, BinStat, Coord, MissingColumnAction, ValueSource};
+
+    fn record(start: Coord, values: &[&str]) -> BedRecord {
+        bed_record("c", start, start + 10, values)
+    }
+
+    #[test]
+    fn yields_a_record_for_each_input_record() {
+        let opts = ConvertOptions::default();
+        let records = vec![record(0, &["1"]), record(10, &["2.5"])];
+        let converted: Vec<_> = to_bedgraph(records, &opts)
+            .map(|record| record.map(|record| (record.start, record.end, record.value)))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(converted, vec![(0, 10, 1.0), (10, 20, 2.5)]);
+    }
+
+    #[test]
+    fn bins_are_yielded_once_the_input_ends() {
+        let opts = ConvertOptions {
+            bins: Some(BinOptions {
+                size: 20,
+                stat: BinStat::Mean,
+                tiles: None,
+            }),
+            ..ConvertOptions::default()
+        };
+        let records = vec![record(0, &["1"]), record(10, &["3"])];
+        let converted: Vec<_> = to_bedgraph(records, &opts)
+            .map(|record| record.map(|record| (record.start, record.end, record.value)))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(converted, vec![(0, 20, 2.0)]);
+    }
+
+    #[test]
+    fn errors_end_the_iteration() {
+        let opts = ConvertOptions {
+            value: ValueSource::Column(0),
+            missing_column: MissingColumnAction::Error,
+            ..ConvertOptions::default()
+        };
+        let records = vec![record(0, &["1"]), record(10, &[]), record(20, &["3"])];
+        let mut converted = to_bedgraph(records, &opts);
+        assert_eq!(converted.next().unwrap().unwrap().start, 0);
+        let error = converted.next().unwrap().unwrap_err();
+        assert!(matches!(error, Error::Parse { line: 2, .. }));
+        assert!(converted.next().is_none());
+    }
+}
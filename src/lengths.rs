@@ -0,0 +1,30 @@
+use crate::{BedGraphRecord, RecordWriter, ValueFormat};
+use std::io::Write;
+
+/// Writes a `length<TAB>value` line for each record, without coordinates or a header, for
+/// plotting values against interval lengths
+pub struct LengthsWriter<W: Write> {
+    writer: W,
+    value_format: ValueFormat,
+}
+
+impl<W: Write> LengthsWriter<W> {
+    pub fn new(writer: W, value_format: ValueFormat) -> Self {
+        LengthsWriter {
+            writer,
+            value_format,
+        }
+    }
+}
+
+impl<W: Write> RecordWriter for LengthsWriter<W> {
+    fn write(&mut self, record: &BedGraphRecord) -> std::io::Result<()> {
+        write!(self.writer, "{}\t", record.end.saturating_sub(record.start))?;
+        self.value_format.write(&mut self.writer, record.value)?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
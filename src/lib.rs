@@ -0,0 +1,951 @@
+//! Converts BED records into a bedGraph track.
+//!
+//! [`convert`] runs the whole parse-transform-write pipeline over any reader and writer,
+//! which is what the command line tool uses under the hood. [`Converter`] does the same for
+//! several inputs that end up in one track, and [`to_bedgraph`] yields the converted
+//! records of any iterator of [`BedRecord`]s instead of writing them.
+
+mod bed;
+mod bedgraph;
+mod bigwig;
+mod bins;
+mod chrom_filter;
+mod chrom_sizes;
+mod dedup;
+mod gzip;
+mod index;
+mod iter;
+mod lengths;
+mod merge;
+mod order;
+mod peaks;
+mod plan;
+mod quantize;
+mod ratio;
+mod smooth;
+mod split;
+mod summary;
+mod tail;
+#[cfg(test)]
+mod testing;
+mod transform;
+mod zscore;
+#[cfg(feature = "zstd")]
+mod zstd_encoder;
+
+pub use bed::{BedItem, BedParser, BedRecord};
+pub use bedgraph::{BedColumns, BedGraphRecord, BedGraphWriter, ValueFormat, DEFAULT_HEADER};
+pub use bigwig::BigWigWriter;
+pub use bins::{BinOptions, BinStat};
+pub use chrom_filter::ChromFilter;
+pub use chrom_sizes::ChromSizes;
+pub use dedup::{DedupBy, DedupKeep, DedupOptions};
+pub use gzip::ParallelGzEncoder;
+pub use index::{IndexEntry, OutputIndex};
+pub use iter::to_bedgraph;
+pub use lengths::LengthsWriter;
+pub use peaks::{PeakOptions, PeakStat};
+pub use quantize::{QuantizeMode, QuantizeOptions};
+pub use ratio::{RatioOptions, Track};
+pub use split::convert_split;
+pub use summary::{ChromSummary, Summary, SummaryFormat};
+pub use transform::Transform;
+pub use zscore::Moments;
+#[cfg(feature = "zstd")]
+pub use zstd_encoder::ZstdEncoder;
+
+use bins::Binner;
+use clap::ValueEnum;
+use dedup::Deduplicator;
+use merge::Merger;
+use order::{OutputVerifier, Regrouper, SortChecker};
+use peaks::PeakCaller;
+use ratio::Ratio;
+use smooth::MedianSmoother;
+use std::fmt;
+use std::io::{BufRead, Write};
+use tail::Tail;
+use zscore::ZScorer;
+
+/// The type of chromosome coordinates. 32 bits are enough for any real genome, and the
+/// `u64-coords` feature widens them for larger synthetic ones
+#[cfg(not(feature = "u64-coords"))]
+pub type Coord = u32;
+#[cfg(feature = "u64-coords")]
+pub type Coord = u64;
+
+/// The number of bases from `start` to `end`, as a u64 so that lengths can be added up
+/// without overflowing. It is 0 when `end` is not after `start`
+// Widening does nothing to u64 coordinates
+#[cfg_attr(feature = "u64-coords", allow(clippy::useless_conversion))]
+pub(crate) fn bases(start: Coord, end: Coord) -> u64 {
+    u64::from(end.saturating_sub(start))
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// A file could not be opened
+    Open {
+        path: String,
+        source: std::io::Error,
+    },
+    /// An output file could not be created
+    Create {
+        path: String,
+        source: std::io::Error,
+    },
+    /// The input could not be read
+    Read(std::io::Error),
+    /// The output could not be written
+    Write(std::io::Error),
+    /// A line of the input is not a valid record
+    Parse { line: usize, message: String },
+    /// A record is out of order in input that has to be sorted
+    Unsorted { line: usize, message: String },
+    /// A record that was about to be written breaks the rules of bedGraph
+    InvalidOutput { number: u64, message: String },
+    /// One of the inputs listed on a line of a manifest failed
+    Manifest {
+        line: usize,
+        path: String,
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    pub(crate) fn parse(line: usize, message: impl Into<String>) -> Self {
+        Error::Parse {
+            line,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn unsorted(line: usize, message: impl Into<String>) -> Self {
+        Error::Unsorted {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Open { path, source } => write!(f, "Could not open {}: {}", path, source),
+            Error::Create { path, source } => write!(f, "Could not create {}: {}", path, source),
+            Error::Read(e) => write!(f, "Could not read input: {}", e),
+            Error::Write(e) => write!(f, "Could not write output: {}", e),
+            Error::Parse { line, message } => write!(f, "Line {}: {}", line, message),
+            Error::Unsorted { line, message } => {
+                write!(f, "Line {}: Input is not sorted. {}", line, message)
+            }
+            Error::InvalidOutput { number, message } => {
+                write!(
+                    f,
+                    "Output record {} is not valid bedGraph: {}",
+                    number, message
+                )
+            }
+            Error::Manifest { line, path, source } => {
+                write!(f, "Manifest line {} ({}): {}", line, path, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Open { source, .. } | Error::Create { source, .. } => Some(source),
+            Error::Read(e) | Error::Write(e) => Some(e),
+            Error::Parse { .. } | Error::Unsorted { .. } | Error::InvalidOutput { .. } => None,
+            Error::Manifest { source, .. } => Some(source.as_ref()),
+        }
+    }
+}
+
+/// Where the value of each bedGraph record is read from
+#[derive(Clone, Debug)]
+pub enum ValueSource {
+    /// The BED score column
+    Score,
+    /// A column after the score column, counting from 0
+    Column(usize),
+    /// A key in the attributes stored in the name column
+    NameAttribute(String),
+}
+
+impl ValueSource {
+    /// Reads the value of a record, or `None` when the record should be skipped
+    fn extract(
+        &self,
+        record: &BedRecord,
+        line: usize,
+        missing_column: MissingColumnAction,
+    ) -> Result<Option<f64>, Error> {
+        let missing = |message: String| match missing_column {
+            MissingColumnAction::Skip => Ok(None),
+            MissingColumnAction::Zero => Ok(Some(0.0)),
+            MissingColumnAction::Error => Err(Error::parse(line, message)),
+        };
+        match self {
+            ValueSource::Score => Ok(Some(record.score)),
+            ValueSource::Column(index) => {
+                let Some(field) = record.values.get(*index) else {
+                    return missing(format!(
+                        "Could not find column index {} in record. Remember that the index is 0-based and the first value is after the score column",
+                        index
+                    ));
+                };
+                field
+                    .parse::<f64>()
+                    .map(Some)
+                    .map_err(|_| Error::parse(line, format!("Could not parse value '{}'", field)))
+            }
+            // A name without the key, or with a value that is not a number, has no value
+            // just like a record without the value column
+            ValueSource::NameAttribute(key) => {
+                match record
+                    .name_attribute(key)
+                    .and_then(|value| value.parse::<f64>().ok())
+                {
+                    Some(value) => Ok(Some(value)),
+                    None => missing(format!(
+                        "Could not parse value of {} from name '{}'",
+                        key, record.name
+                    )),
+                }
+            }
+        }
+    }
+}
+
+/// What to do with a record that does not have the value column at all, or whose name
+/// does not have the value key as a number
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingColumnAction {
+    /// Leave the record out
+    Skip,
+    /// Use 0 as the value
+    Zero,
+    /// Stop with an error
+    #[default]
+    Error,
+}
+
+/// Whether `offset` is added to the value before or after it is scaled
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OffsetOrder {
+    /// `value * scale + offset`
+    #[default]
+    AfterScale,
+    /// `(value + offset) * scale`
+    BeforeScale,
+}
+
+/// Adds or removes the `chr` at the start of chromosome names, as the two common naming
+/// styles differ by
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChromPrefix {
+    /// Put `chr` in front of names that do not start with it
+    Add,
+    /// Take `chr` off the front of names that start with it
+    Strip,
+}
+
+impl ChromPrefix {
+    /// Renames a chromosome, leaving names that are already in the wanted style alone
+    pub fn apply(self, chrom: &mut String) {
+        match (self, chrom.starts_with("chr")) {
+            (ChromPrefix::Add, false) => chrom.insert_str(0, "chr"),
+            (ChromPrefix::Strip, true) => {
+                chrom.drain(.."chr".len());
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConvertOptions {
+    /// Where the value of each record is read from
+    pub value: ValueSource,
+    /// The score of records whose score column is missing or not a number
+    pub default_score: f64,
+    /// Rename every chromosome as it is read, before anything else looks at the name
+    pub chrom_prefix: Option<ChromPrefix>,
+    /// Only convert the records of these chromosomes
+    pub chroms: Option<ChromFilter>,
+    /// Fail on records whose chromosome is not in these sizes or that end past its
+    /// length, as bigWig output needs
+    pub chrom_sizes: Option<ChromSizes>,
+    /// Collapse duplicate records into one, before any of the options below
+    pub dedup: Option<DedupOptions>,
+    /// Divide each value by a control track, splitting records where the control
+    /// intervals change
+    pub ratio: Option<RatioOptions>,
+    /// Replace each value with the median of a window of this many records around it
+    /// on the same chromosome
+    pub median_window: Option<usize>,
+    /// Summarise the values in fixed-size windows
+    pub bins: Option<BinOptions>,
+    /// Call peaks instead of writing every record
+    pub peaks: Option<PeakOptions>,
+    /// Merge abutting records whose values differ from the first of their run by at most
+    /// this much into one record with their length-weighted mean. 0 merges equal values
+    pub merge_tolerance: Option<f64>,
+    /// Let `merge_tolerance` also merge records that start at most this many bases after
+    /// the end of the one before, spanning the gap
+    pub merge_gap: Coord,
+    /// How values are written
+    pub value_format: ValueFormat,
+    /// What the bedGraph output starts with, or `None` for no header
+    pub header: Option<String>,
+    /// Write BED records that keep all of the input columns, with the value in place of
+    /// the score, instead of bedGraph records
+    pub bed_columns: bool,
+    /// Pad the columns of the output so they line up in a terminal. This is not valid
+    /// bedGraph any more
+    pub pretty: bool,
+    /// A factor every value is multiplied by
+    pub scale: f64,
+    /// A constant added to every value, which may make it negative
+    pub offset: f64,
+    /// Whether `offset` goes on before or after `scale`, which takes in the scale of the
+    /// input as well
+    pub offset_order: OffsetOrder,
+    /// An expression that computes the value written for each record, applied after
+    /// `scale` and `offset`
+    pub transform: Option<Transform>,
+    /// Snap each value onto a fixed set of levels, after `transform`
+    pub quantize: Option<QuantizeOptions>,
+    /// Replace each value with 1 when it is at least this threshold and 0 otherwise, after
+    /// `quantize`
+    pub binarize: Option<f64>,
+    /// Drop the records that `binarize` would give 0
+    pub binarize_drop: bool,
+    /// Bases every record is moved by, towards the chromosome end when positive
+    pub shift: i64,
+    /// Bases added to both sides of every record, after `shift`
+    pub extend: u32,
+    /// Replace each written value with its z-score against all written values. This holds
+    /// the whole output in memory
+    pub zscore: bool,
+    /// Collect per-chromosome statistics of what is written, into [`ConvertStats::summary`]
+    pub summary: bool,
+    /// Check that what is written is sorted, non-overlapping bedGraph, failing at the
+    /// first record that is not
+    pub verify_output: bool,
+    /// Copy `#` comment lines of the input into the output as they are read
+    pub keep_comments: bool,
+    /// Fail on input that is not sorted by start within contiguous chromosome blocks
+    pub check_sorted: bool,
+    /// Promise that the input is sorted, so that binning can pass on each bin as soon as
+    /// it is complete instead of holding all of them. The order is checked as with
+    /// `check_sorted`
+    pub assume_sorted: bool,
+    /// Reunite chromosomes whose records are split into several blocks, buffering the
+    /// whole input in memory
+    pub regroup: bool,
+    /// Stop after this many records with a value have been read across all inputs
+    pub head: Option<u64>,
+    /// Only keep the last this many records that have a value, after `head`. Up to this
+    /// many records are held in memory
+    pub tail: Option<usize>,
+    /// What to do with records that are too short to have the value column
+    pub missing_column: MissingColumnAction,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        ConvertOptions {
+            value: ValueSource::Column(0),
+            default_score: 0.0,
+            chrom_prefix: None,
+            chroms: None,
+            chrom_sizes: None,
+            dedup: None,
+            ratio: None,
+            median_window: None,
+            bins: None,
+            peaks: None,
+            merge_tolerance: None,
+            merge_gap: 0,
+            value_format: ValueFormat::default(),
+            header: Some(DEFAULT_HEADER.to_string()),
+            bed_columns: false,
+            pretty: false,
+            scale: 1.0,
+            offset: 0.0,
+            offset_order: OffsetOrder::default(),
+            transform: None,
+            quantize: None,
+            binarize: None,
+            binarize_drop: false,
+            shift: 0,
+            extend: 0,
+            zscore: false,
+            summary: false,
+            verify_output: false,
+            keep_comments: false,
+            check_sorted: false,
+            assume_sorted: false,
+            regroup: false,
+            head: None,
+            tail: None,
+            missing_column: MissingColumnAction::default(),
+        }
+    }
+}
+
+/// Counts of what was written by [`convert`]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConvertStats {
+    /// The number of bedGraph records written
+    pub records: u64,
+    /// The total length of the written records
+    pub bases: u64,
+    /// The number of records whose start was held at 0 by `shift` or `extend`
+    pub clamped: u64,
+    /// The number of records that `shift` moved entirely below 0. They are dropped, and
+    /// not counted in `clamped`
+    pub emptied: u64,
+    /// The number of records folded into another one by `dedup`
+    pub collapsed: u64,
+    /// What the values were z-scored against, when they were
+    pub zscore: Option<Moments>,
+    /// Statistics of the written values, when they were asked for
+    pub summary: Option<Summary>,
+}
+
+impl ConvertStats {
+    fn add(&mut self, record: &BedGraphRecord) {
+        self.records += 1;
+        self.bases += bases(record.start, record.end);
+    }
+}
+
+/// Something that converted records are written to
+pub trait RecordWriter {
+    fn write(&mut self, record: &BedGraphRecord) -> std::io::Result<()>;
+
+    /// Writes a comment line from the input as it is. Formats without comments drop it
+    fn write_comment(&mut self, _comment: &str) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once after the last record to flush the output and write anything that
+    /// can only be written at the end
+    fn close(&mut self) -> std::io::Result<()>;
+}
+
+/// A step that records pass through once their value has been read. Stages that need
+/// to see more than one record hold records back and pass them on later
+pub(crate) trait Stage {
+    /// Takes a record, adding any records that are ready to go on to `out`
+    fn push(&mut self, record: BedGraphRecord, out: &mut Vec<BedGraphRecord>) -> Result<(), Error>;
+
+    /// Adds the records still held back to `out` once the input has ended
+    fn finish(&mut self, out: &mut Vec<BedGraphRecord>) -> Result<(), Error>;
+}
+
+/// Reads BED records from `reader` and writes them to `writer` as a bedGraph track
+pub fn convert<R: BufRead, W: Write>(
+    reader: R,
+    writer: W,
+    opts: &ConvertOptions,
+) -> Result<ConvertStats, Error> {
+    let mut converter = Converter::new(writer, opts)?;
+    converter.add_input(reader, 1.0)?;
+    converter.finish()
+}
+
+/// Writes the records of any number of inputs into a single bedGraph track, for when
+/// [`convert`] with its one input is not enough
+pub struct Converter<'a, O: RecordWriter> {
+    writer: O,
+    opts: &'a ConvertOptions,
+    sort_checker: Option<SortChecker>,
+    verifier: Option<OutputVerifier>,
+    dedup: Option<Deduplicator>,
+    stages: Vec<Box<dyn Stage>>,
+    zscore: Option<ZScorer>,
+    normalized: Option<Box<dyn RecordWriter + 'a>>,
+    records_read: u64,
+    stats: ConvertStats,
+}
+
+impl<'a, W: Write> Converter<'a, BedGraphWriter<W>> {
+    /// Starts a bedGraph track by writing its header
+    pub fn new(writer: W, opts: &'a ConvertOptions) -> Result<Self, Error> {
+        let mut writer =
+            BedGraphWriter::with_header(writer, opts.value_format, opts.header.as_deref())
+                .map_err(Error::Write)?;
+        writer.set_bed_columns(opts.bed_columns);
+        writer.set_pretty(opts.pretty);
+        Ok(Converter::with_writer(writer, opts))
+    }
+}
+
+impl<'a, O: RecordWriter> Converter<'a, O> {
+    /// Converts into any kind of output
+    pub fn with_writer(writer: O, opts: &'a ConvertOptions) -> Self {
+        let mut stages: Vec<Box<dyn Stage>> = Vec::new();
+        if let Some(count) = opts.tail {
+            stages.push(Box::new(Tail::new(count)));
+        }
+        if opts.regroup {
+            stages.push(Box::new(Regrouper::default()));
+        }
+        if let Some(ratio) = &opts.ratio {
+            stages.push(Box::new(Ratio::new(ratio.clone())));
+        }
+        if let Some(window) = opts.median_window {
+            stages.push(Box::new(MedianSmoother::new(window)));
+        }
+        if let Some(bins) = &opts.bins {
+            stages.push(Box::new(Binner::new(bins.clone(), opts.assume_sorted)));
+        }
+        if let Some(peaks) = &opts.peaks {
+            stages.push(Box::new(PeakCaller::new(peaks.clone())));
+        }
+        if let Some(tolerance) = opts.merge_tolerance {
+            stages.push(Box::new(Merger::new(tolerance, opts.merge_gap)));
+        }
+        Converter {
+            writer,
+            opts,
+            sort_checker: (opts.check_sorted || opts.assume_sorted)
+                .then(|| SortChecker::new(opts.regroup)),
+            verifier: opts.verify_output.then(OutputVerifier::default),
+            dedup: opts
+                .dedup
+                .as_ref()
+                .map(|dedup| Deduplicator::new(dedup, opts.assume_sorted)),
+            stages,
+            zscore: opts.zscore.then(ZScorer::default),
+            normalized: None,
+            records_read: 0,
+            stats: ConvertStats {
+                summary: opts.summary.then(Summary::default),
+                ..ConvertStats::default()
+            },
+        }
+    }
+
+    /// Sends the z-scored records to `writer` instead, so that the output gets the values
+    /// as they were before `zscore` and can be written as they come. The two outputs have
+    /// the same records in the same order, and the stats are of the first one. Nothing is
+    /// written to `writer` without `zscore`
+    pub fn set_normalized_writer(&mut self, writer: impl RecordWriter + 'a) {
+        self.normalized = Some(Box::new(writer));
+    }
+
+    /// Converts every record of `reader`, multiplying its values by `scale` on top of the
+    /// scale in the options
+    pub fn add_input<R: BufRead>(&mut self, reader: R, scale: f64) -> Result<(), Error> {
+        let mut parser = BedParser::with_default_score(
+            bed::MaybeCompressed::new(reader)?,
+            self.opts.default_score,
+        );
+        let scale = scale * self.opts.scale;
+
+        while !self.reached_head() {
+            let Some(item) = parser.next_item() else {
+                break;
+            };
+            let record = match item? {
+                BedItem::Record(record) => record,
+                BedItem::Comment(comment) if self.opts.keep_comments => {
+                    self.writer.write_comment(&comment).map_err(Error::Write)?;
+                    continue;
+                }
+                BedItem::Comment(_) => continue,
+            };
+            self.add_record(record, parser.line_number(), scale)?;
+        }
+        Ok(())
+    }
+
+    /// Takes a record through everything from the chromosome filter to the stages.
+    /// `line_number` is where errors about the record point. Only records that make it
+    /// to the stages count towards `head`
+    fn add_record(
+        &mut self,
+        record: BedRecord,
+        line_number: usize,
+        scale: f64,
+    ) -> Result<(), Error> {
+        let Some(bg_record) = self.prepare(record, line_number, scale)? else {
+            return Ok(());
+        };
+        self.records_read += 1;
+        match self.dedup.as_mut() {
+            Some(dedup) => {
+                let mut out = Vec::new();
+                dedup.push(bg_record, &mut out);
+                self.run_stages(0, out)?;
+            }
+            None => self.run_stages(0, vec![bg_record])?,
+        }
+        Ok(())
+    }
+
+    /// Turns a record into the bedGraph record that goes on to the stages, or `None` when
+    /// the record is filtered out or has no value
+    pub(crate) fn prepare(
+        &mut self,
+        record: BedRecord,
+        line_number: usize,
+        scale: f64,
+    ) -> Result<Option<BedGraphRecord>, Error> {
+        let mut record = record;
+        if let Some(prefix) = self.opts.chrom_prefix {
+            prefix.apply(&mut record.chrom);
+        }
+        if let Some(chroms) = &self.opts.chroms {
+            if !chroms.keeps(&record.chrom) {
+                return Ok(None);
+            }
+        }
+        if let Some(checker) = self.sort_checker.as_mut() {
+            checker.check(&record, line_number)?;
+        }
+        let tiles = self.opts.bins.as_ref().and_then(|bins| bins.tiles.as_ref());
+        let missing = |sizes: &ChromSizes| sizes.get(&record.chrom).is_none();
+        if tiles.is_some_and(missing) || self.opts.chrom_sizes.as_ref().is_some_and(missing) {
+            return Err(Error::parse(
+                line_number,
+                format!("Chromosome {} is not in the chrom sizes", record.chrom),
+            ));
+        }
+        let value = self
+            .opts
+            .value
+            .extract(&record, line_number, self.opts.missing_column)?;
+        let offset = self.opts.offset;
+        let value = value.map(|value| match self.opts.offset_order {
+            OffsetOrder::AfterScale => value * scale + offset,
+            OffsetOrder::BeforeScale => (value + offset) * scale,
+        });
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        let (start, end, clamped) = self.move_coords(record.start, record.end);
+        let size = self
+            .opts
+            .chrom_sizes
+            .as_ref()
+            .and_then(|sizes| sizes.get(&record.chrom));
+        if let Some(size) = size.filter(|&size| end > size) {
+            return Err(Error::parse(
+                line_number,
+                format!(
+                    "Record {}:{}-{} ends past the chromosome length {}",
+                    record.chrom, start, end, size
+                ),
+            ));
+        }
+        if end <= start && record.end > record.start {
+            self.stats.emptied += 1;
+            return Ok(None);
+        }
+        self.stats.clamped += clamped as u64;
+        let columns = self.opts.bed_columns.then(|| {
+            Box::new(BedColumns {
+                name: record.name,
+                values: record.values,
+            })
+        });
+        let mut bg_record = BedGraphRecord {
+            chrom: record.chrom,
+            start,
+            end,
+            value,
+            columns,
+        };
+        if let Some(transform) = &self.opts.transform {
+            bg_record.value = transform.apply(&bg_record);
+        }
+        if let Some(quantize) = &self.opts.quantize {
+            bg_record.value = quantize.apply(bg_record.value);
+        }
+        if let Some(threshold) = self.opts.binarize {
+            let present = bg_record.value >= threshold;
+            if !present && self.opts.binarize_drop {
+                return Ok(None);
+            }
+            bg_record.value = match present {
+                true => 1.0,
+                false => 0.0,
+            };
+        }
+        Ok(Some(bg_record))
+    }
+
+    /// Applies `shift` and `extend` to a record's coordinates. They saturate at 0 and
+    /// `Coord::MAX` instead of wrapping, and the flag says whether the start had to be held
+    /// at 0
+    fn move_coords(&self, start: Coord, end: Coord) -> (Coord, Coord, bool) {
+        let (shift, extend) = (self.opts.shift as i128, self.opts.extend as i128);
+        let clamp = |coord: i128| coord.clamp(0, Coord::MAX as i128) as Coord;
+        let start_wanted = start as i128 + shift - extend;
+        let end = clamp(end as i128 + shift + extend);
+        (clamp(start_wanted).min(end), end, start_wanted < 0)
+    }
+
+    fn reached_head(&self) -> bool {
+        self.opts.head.is_some_and(|head| self.records_read >= head)
+    }
+
+    /// Passes records through the stages from `first` on and writes whatever comes out
+    fn run_stages(&mut self, first: usize, records: Vec<BedGraphRecord>) -> Result<(), Error> {
+        let mut records = records;
+        for stage in &mut self.stages[first..] {
+            let mut out = Vec::new();
+            for record in records {
+                stage.push(record, &mut out)?;
+            }
+            records = out;
+        }
+        for record in records {
+            if self.zscore.is_none() || self.normalized.is_some() {
+                self.write(&record)?;
+            }
+            if let Some(zscore) = self.zscore.as_mut() {
+                zscore.push(record);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, record: &BedGraphRecord) -> Result<(), Error> {
+        if let Some(verifier) = self.verifier.as_mut() {
+            verifier.check(record, self.stats.records + 1)?;
+        }
+        // Writers turn down records that their format cannot hold as invalid data
+        self.writer.write(record).map_err(|e| match e.kind() {
+            std::io::ErrorKind::InvalidData => Error::InvalidOutput {
+                number: self.stats.records + 1,
+                message: e.to_string(),
+            },
+            _ => Error::Write(e),
+        })?;
+        self.stats.add(record);
+        if let Some(summary) = self.stats.summary.as_mut() {
+            summary.add(record);
+        }
+        Ok(())
+    }
+
+    /// Writes anything still pending and flushes the output
+    pub fn finish(self) -> Result<ConvertStats, Error> {
+        self.finish_into_writer().map(|(stats, _)| stats)
+    }
+
+    /// Like [`Converter::finish`], but also hands back the writer, e.g. to get at the
+    /// index of a [`BedGraphWriter`]
+    pub fn finish_into_writer(mut self) -> Result<(ConvertStats, O), Error> {
+        if let Some(mut dedup) = self.dedup.take() {
+            let mut out = Vec::new();
+            dedup.finish(&mut out);
+            self.stats.collapsed = dedup.collapsed;
+            self.run_stages(0, out)?;
+        }
+        for index in 0..self.stages.len() {
+            let mut out = Vec::new();
+            self.stages[index].finish(&mut out)?;
+            self.run_stages(index + 1, out)?;
+        }
+        if let Some(zscore) = self.zscore.take() {
+            let (moments, records) = zscore.finish();
+            for record in &records {
+                match self.normalized.as_mut() {
+                    Some(normalized) => normalized.write(record).map_err(Error::Write)?,
+                    None => self.write(record)?,
+                }
+            }
+            self.stats.zscore = Some(moments);
+        }
+        if let Some(normalized) = self.normalized.as_mut() {
+            normalized.close().map_err(Error::Write)?;
+        }
+        self.writer.close().map_err(Error::Write)?;
+        Ok((self.stats, self.writer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Converts `input` without a header, handing back the output as text
+    fn convert_str(input: &str, opts: &ConvertOptions) -> Result<String, Error> {
+        let opts = ConvertOptions {
+            header: None,
+            ..opts.clone()
+        };
+        let mut output = Vec::new();
+        convert(input.as_bytes(), &mut output, &opts)?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn moved_starts_are_held_at_zero() {
+        let input = "c\t0\t10\t.\t1\nc\t1\t10\t.\t1\nc\t20\t30\t.\t1\n";
+        let run = |shift, extend| {
+            let opts = ConvertOptions {
+                value: ValueSource::Score,
+                header: None,
+                shift,
+                extend,
+                ..ConvertOptions::default()
+            };
+            let mut output = Vec::new();
+            let stats = convert(input.as_bytes(), &mut output, &opts).unwrap();
+            (String::from_utf8(output).unwrap(), stats.clamped)
+        };
+        assert_eq!(
+            run(-5, 0),
+            ("c\t0\t5\t1\nc\t0\t5\t1\nc\t15\t25\t1\n".to_string(), 2)
+        );
+        assert_eq!(
+            run(0, 5),
+            ("c\t0\t15\t1\nc\t0\t15\t1\nc\t15\t35\t1\n".to_string(), 2)
+        );
+    }
+
+    #[test]
+    fn records_shifted_past_zero_are_dropped() {
+        let opts = ConvertOptions {
+            value: ValueSource::Score,
+            header: None,
+            shift: -15,
+            ..ConvertOptions::default()
+        };
+        let mut output = Vec::new();
+        let input = "c\t0\t10\t.\t1\nc\t10\t20\t.\t2\nc\t20\t30\t.\t3\n";
+        let stats = convert(input.as_bytes(), &mut output, &opts).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "c\t0\t5\t2\nc\t5\t15\t3\n"
+        );
+        assert_eq!((stats.emptied, stats.clamped), (1, 1));
+    }
+
+    #[test]
+    fn verified_output_rejects_empty_records() {
+        let opts = ConvertOptions {
+            value: ValueSource::Score,
+            verify_output: true,
+            ..ConvertOptions::default()
+        };
+        let error = convert_str("c\t0\t10\t.\t1\nc\t10\t10\t.\t1\n", &opts).unwrap_err();
+        assert!(matches!(error, Error::InvalidOutput { number: 2, .. }));
+    }
+
+    #[test]
+    fn short_records_follow_missing_column_action() {
+        // The second record loses the value column partway through the input
+        let input = "c\t0\t10\t.\t0\t5\nc\t10\t20\t.\t0\nc\t20\t30\t.\t0\t7\n";
+        let opts = |missing_column| ConvertOptions {
+            missing_column,
+            ..ConvertOptions::default()
+        };
+        assert_eq!(
+            convert_str(input, &opts(MissingColumnAction::Skip)).unwrap(),
+            "c\t0\t10\t5\nc\t20\t30\t7\n"
+        );
+        assert_eq!(
+            convert_str(input, &opts(MissingColumnAction::Zero)).unwrap(),
+            "c\t0\t10\t5\nc\t10\t20\t0\nc\t20\t30\t7\n"
+        );
+        let error = convert_str(input, &opts(MissingColumnAction::Error)).unwrap_err();
+        assert!(matches!(error, Error::Parse { line: 2, .. }));
+    }
+
+    #[test]
+    fn name_attributes_follow_missing_column_action() {
+        let input = "c\t0\t10\tsignal=2\t0\nc\t10\t20\tother=1\t0\nc\t20\t30\tsignal=x\t0\n";
+        let opts = |missing_column| ConvertOptions {
+            value: ValueSource::NameAttribute("signal".to_string()),
+            missing_column,
+            ..ConvertOptions::default()
+        };
+        assert_eq!(
+            convert_str(input, &opts(MissingColumnAction::Skip)).unwrap(),
+            "c\t0\t10\t2\n"
+        );
+        assert_eq!(
+            convert_str(input, &opts(MissingColumnAction::Zero)).unwrap(),
+            "c\t0\t10\t2\nc\t10\t20\t0\nc\t20\t30\t0\n"
+        );
+        let error = convert_str(input, &opts(MissingColumnAction::Error)).unwrap_err();
+        assert!(matches!(error, Error::Parse { line: 2, .. }));
+    }
+
+    #[test]
+    fn tiles_reject_chromosomes_missing_from_the_sizes() {
+        let sizes = ChromSizes::read("a\t100\n".as_bytes()).unwrap();
+        let opts = ConvertOptions {
+            bins: Some(BinOptions {
+                size: 50,
+                stat: BinStat::Mean,
+                tiles: Some(sizes),
+            }),
+            ..ConvertOptions::default()
+        };
+        let error = convert_str("a\t0\t10\tn\t0\t1\nb\t0\t10\tn\t0\t1\n", &opts).unwrap_err();
+        assert!(matches!(error, Error::Parse { line: 2, .. }));
+    }
+
+    #[test]
+    fn chrom_prefixes_are_added_and_stripped_once() {
+        let apply = |prefix: ChromPrefix, chrom: &str| {
+            let mut chrom = chrom.to_string();
+            prefix.apply(&mut chrom);
+            chrom
+        };
+        for (chrom, added, stripped) in [
+            ("chr1", "chr1", "1"),
+            ("1", "chr1", "1"),
+            ("chrUn_KI270302v1", "chrUn_KI270302v1", "Un_KI270302v1"),
+        ] {
+            assert_eq!(apply(ChromPrefix::Add, chrom), added);
+            assert_eq!(apply(ChromPrefix::Add, added), added);
+            assert_eq!(apply(ChromPrefix::Strip, chrom), stripped);
+            assert_eq!(apply(ChromPrefix::Strip, stripped), stripped);
+        }
+    }
+
+    #[test]
+    fn head_counts_only_records_with_a_value() {
+        let input =
+            "c\t0\t10\tn\t0\nc\t10\t20\tn\t0\t1\nc\t20\t30\tn\t0\t0.5\nc\t30\t40\tn\t0\t2\n";
+        let opts = ConvertOptions {
+            missing_column: MissingColumnAction::Skip,
+            binarize: Some(1.0),
+            binarize_drop: true,
+            head: Some(2),
+            ..ConvertOptions::default()
+        };
+        assert_eq!(
+            convert_str(input, &opts).unwrap(),
+            "c\t10\t20\t1\nc\t30\t40\t1\n"
+        );
+    }
+
+    #[test]
+    fn chrom_sizes_reject_records_outside_them() {
+        let opts = ConvertOptions {
+            chrom_sizes: Some(ChromSizes::read("a\t100\n".as_bytes()).unwrap()),
+            ..ConvertOptions::default()
+        };
+        let line =
+            |line: usize| move |error| matches!(error, Error::Parse { line: l, .. } if l == line);
+        let missing = convert_str("a\t0\t10\tn\t0\t1\nb\t0\t10\tn\t0\t1\n", &opts);
+        assert!(missing.is_err_and(line(2)));
+        let past_end = convert_str("a\t0\t10\tn\t0\t1\na\t90\t110\tn\t0\t1\n", &opts);
+        assert!(past_end.is_err_and(line(2)));
+    }
+}
@@ -1,165 +1,1202 @@
-use clap::Parser;
-use std::fs::File;
-use std::io::BufRead;
-use std::io::BufReader;
+#[cfg(feature = "zstd")]
+use bed_to_bedgraph::ZstdEncoder;
+use bed_to_bedgraph::{
+    convert_split, BedGraphWriter, BigWigWriter, BinOptions, BinStat, ChromFilter, ChromPrefix,
+    ChromSizes, ConvertOptions, ConvertStats, Converter, Coord, DedupBy, DedupKeep, DedupOptions,
+    Error, LengthsWriter, MissingColumnAction, OffsetOrder, ParallelGzEncoder, PeakOptions,
+    PeakStat, QuantizeMode, QuantizeOptions, RatioOptions, RecordWriter, SummaryFormat, Track,
+    Transform, ValueFormat, ValueSource, DEFAULT_HEADER,
+};
+use clap::{Parser, ValueEnum};
+use regex::Regex;
+use std::fs::{File, OpenOptions};
 use std::io::BufWriter;
 use std::io::Write;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Bedgraph,
+    Bigwig,
+    Bed,
+    Lengths,
+}
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = EXIT_CODES)]
 struct Cli {
-    /// The input bed file
-    #[clap(short, long)]
-    input: String,
+    /// The input bed file, or `-` for stdin, which may be gzip-compressed. It is read front
+    /// to back once, so pipes and FIFOs work as well as files
+    #[clap(short, long, required_unless_present = "manifest")]
+    input: Option<String>,
+
+    /// Print the steps the conversion would go through, in order, and stop without
+    /// reading the input or writing any output
+    #[clap(long)]
+    explain: bool,
+
+    /// A file listing several input bed files, one `path<TAB>scale` per line, which are
+    /// converted in order into a single output with each file's values multiplied by its
+    /// scale. Paths are relative to the current directory
+    #[clap(long, conflicts_with = "input")]
+    manifest: Option<String>,
+
+    /// A factor every value is multiplied by. It can be negative to flip the sign of a track
+    #[clap(long, default_value = "1", allow_negative_numbers = true)]
+    scale: f64,
+
+    /// A constant added to every value. It can be negative and take values below zero
+    #[clap(long, default_value = "0", allow_negative_numbers = true)]
+    offset: f64,
+
+    /// Whether --offset is added after the value is multiplied by --scale (and the scale
+    /// of a manifest input) or before
+    #[clap(long, value_enum, default_value = "after-scale")]
+    offset_order: OffsetOrder,
+
+    /// An expression in reverse Polish notation computing each written value after --scale
+    /// and --offset, e.g. "value 1 + ln 2 *". `value`, `start`, `end` and `length` push a property of the
+    /// record, `+ - * / pow min max` take two values and `ln log2 abs` take one
+    #[clap(long, value_name = "EXPR")]
+    transform: Option<Transform>,
+
+    /// Snap each value onto these comma-separated levels, after --transform, e.g.
+    /// "0,0.5,1". See --quantize-mode for how values are mapped
+    #[clap(long, value_name = "LEVELS", value_delimiter = ',', num_args = 1..)]
+    quantize: Vec<f64>,
+
+    /// How --quantize maps a value onto the levels
+    #[clap(long, value_enum, default_value = "nearest")]
+    quantize_mode: QuantizeMode,
+
+    /// Write 1 for records whose value is at least T and 0 for the rest, after --transform
+    /// and --quantize. With --merge this makes a mask of the intervals over T
+    #[clap(long, value_name = "T", allow_negative_numbers = true)]
+    binarize: Option<f64>,
+
+    /// Drop the records --binarize would write as 0
+    #[clap(long, requires = "binarize")]
+    binarize_drop: bool,
+
+    /// Move every record by this many bases, towards the chromosome end when positive.
+    /// Starts that would fall before 0 are held at 0, and records that would end at or
+    /// before 0 are dropped
+    #[clap(long, default_value = "0", allow_negative_numbers = true)]
+    shift: i64,
+
+    /// Add this many bases to both sides of every record, after --shift. Starts that would
+    /// fall before 0 are held at 0
+    #[clap(long, default_value = "0")]
+    extend: u32,
 
     /// The output bedgraph file
     /// If not provided, the output will be printed to stdout
-    #[clap(short, long)]
+    #[clap(short, long, required_if_eq("output_format", "bigwig"))]
     output: Option<String>,
 
+    /// The format of the output. bed output keeps every column of the input records, with
+    /// the value in place of the score and no header. bigWig output needs --output and --chrom-sizes, and the
+    /// records must be sorted and non-overlapping, with each chromosome in a single block
+    /// (see --regroup). Only the index of the bigWig is held in memory. lengths output
+    /// writes `length<TAB>value` for each record, with no coordinates and no header
+    #[clap(long, value_enum, default_value = "bedgraph")]
+    output_format: OutputFormat,
+
+    /// A file of `name<TAB>length` lines giving the length of each chromosome, for bigWig
+    /// output and --tile-size. A `.fai` FASTA index works too, and is told apart by its
+    /// five columns
+    #[clap(long, required_if_eq("output_format", "bigwig"))]
+    chrom_sizes: Option<String>,
+
+    /// Overwrite the output file if it already exists
+    #[clap(long)]
+    force: bool,
+
+    /// Add to the end of the output file instead of replacing it, creating it when it does
+    /// not exist. The header is only written when the file is new or empty, so several
+    /// runs build up a single track
+    #[clap(
+        long,
+        requires = "output",
+        conflicts_with_all = ["force", "compress_cmd", "emit_index", "split_by_chrom"]
+    )]
+    append: bool,
+
+    /// Also copy the output to stdout while writing it to --output, as `tee` would. The
+    /// file is what counts: when stdout is closed early the copy stops and the file is
+    /// still written in full, while any other error on either fails the conversion
+    #[clap(long, requires = "output", conflicts_with_all = ["compress_cmd", "split_by_chrom"])]
+    tee: bool,
+
     /// The index of the column containing the value to graph which can be 'score' or the column index (min 0).
     /// The value must be a number
     #[clap(short, long, default_value = "0")]
     value_column: String,
+
+    /// The score used for records whose score column is missing or not a number, which ends
+    /// up in the output with --value-column score
+    #[clap(long, default_value = "0", allow_negative_numbers = true)]
+    default_score: f64,
+
+    /// What to do with a record that is too short to have the value column. This is
+    /// separate from a value that is there but is not a number, which is always an error.
+    /// With --value-from-name it applies to names without the key or whose value for it
+    /// is not a number
+    #[clap(long, value_enum, default_value = "error")]
+    missing_column_action: MissingColumnAction,
+
+    /// Read the value from a key in the name column instead of a value column, where the name
+    /// holds attributes like `gene_id "X"; signal "5.2";` or `gene_id=X;signal=5.2`
+    #[clap(long, value_name = "KEY")]
+    value_from_name: Option<String>,
+
+    /// Write each value with the shortest representation that reads back as exactly the
+    /// same number, using scientific notation where that is shorter
+    #[clap(long)]
+    lossless: bool,
+
+    /// Round each value to N significant digits, e.g. 0.00123 and 12300 for 3
+    #[clap(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u8).range(1..=17),
+        conflicts_with = "lossless"
+    )]
+    sig_figs: Option<u8>,
+
+    /// Pad the columns with spaces so that they line up when read in a terminal. The
+    /// output is no longer standard bedgraph and strict readers will reject it
+    #[clap(long)]
+    pretty: bool,
+
+    /// A file whose contents are written as the header of the bedgraph output instead of
+    /// `track type=bedGraph`, e.g. a track line with display settings
+    #[clap(long)]
+    header_file: Option<String>,
+
+    /// Write no header to the bedgraph output, even with --header-file
+    #[clap(long)]
+    no_header: bool,
+
+    /// Replace each value with its z-score, (value - mean) / stddev, using the mean and
+    /// standard deviation of all written values, which are reported on stderr. This holds
+    /// the whole output in memory
+    #[clap(long, conflicts_with = "split_by_chrom")]
+    zscore: bool,
+
+    /// Write the z-scored track to this file and the values from before --zscore to
+    /// --output (or stdout), in a single pass. The two have the same records in the same
+    /// order, and only the z-scored one is held in memory
+    #[clap(long, requires = "zscore")]
+    output_normalized: Option<String>,
+
+    /// Add `chr` to the front of every chromosome name that does not have it, or strip it
+    /// from every name that does. This happens as records are read, so the chromosome
+    /// options below and --chrom-sizes see the new names
+    #[clap(long, value_enum)]
+    chrom_prefix: Option<ChromPrefix>,
+
+    /// Only convert this chromosome. Can be given more than once, and adds to --chrom-list
+    /// and --chrom-regex: a record is kept when its chromosome is named by any of them
+    #[clap(long)]
+    chrom: Vec<String>,
+
+    /// Only convert the chromosomes listed in this file, one name per line
+    #[clap(long, value_name = "FILE")]
+    chrom_list: Option<String>,
+
+    /// Only convert chromosomes matching this regular expression, e.g. "^chr[0-9]+$". Can
+    /// be given more than once
+    #[clap(long, value_name = "REGEX")]
+    chrom_regex: Vec<Regex>,
+
+    /// Write statistics of the written values per chromosome and overall: the count, the
+    /// covered bases, min, max, the mean per base and the median record value. Every
+    /// value is held in memory for the median
+    #[clap(long)]
+    stats: bool,
+
+    /// How --stats are written
+    #[clap(long, value_enum, default_value = "table", requires = "stats")]
+    stats_format: SummaryFormat,
+
+    /// Write --stats to this file instead of stderr
+    #[clap(long, value_name = "FILE", requires = "stats")]
+    stats_output: Option<String>,
+
+    /// Check that the records are written sorted within contiguous chromosome blocks and
+    /// without overlaps, failing at the first record that is not. Unlike --check-sorted
+    /// this checks the output, after every other option has been applied
+    #[clap(long)]
+    verify_output: bool,
+
+    /// Copy `#` comment lines of the input into the output as they are read, so that they
+    /// come before the data when they head the input. bigwig output has no comments
+    #[clap(long, conflicts_with = "split_by_chrom")]
+    keep_comments: bool,
+
+    /// Fail if the input is not sorted by start within contiguous chromosome blocks,
+    /// reporting the line where the order breaks
+    #[clap(long)]
+    check_sorted: bool,
+
+    /// Promise that the input is sorted by start within contiguous chromosome blocks, so
+    /// that --bin-size can write each window as soon as it is complete instead of holding
+    /// all of them in memory. The order is still checked, as with --check-sorted
+    #[clap(long)]
+    assume_sorted: bool,
+
+    /// Reunite the records of chromosomes that are split into several blocks in the
+    /// input. This holds the whole input in memory
+    #[clap(long)]
+    regroup: bool,
+
+    /// Only convert the first N records that have a value. Comments, header lines and records
+    /// skipped for a missing value or dropped by --binarize-drop do not count
+    #[clap(long, value_name = "N")]
+    head: Option<u64>,
+
+    /// Only convert the last N records. Comments and header lines do not count, and up to N
+    /// records are held in memory until the input ends
+    #[clap(long, value_name = "N", conflicts_with = "split_by_chrom")]
+    tail: Option<usize>,
+
+    /// Write each chromosome to its own `<chrom>.bedgraph` file in this directory instead
+    /// of writing a single output. The input is held in memory while the chromosomes are
+    /// converted in parallel
+    #[clap(long, value_name = "DIR", conflicts_with_all = ["output", "manifest", "output_format"])]
+    split_by_chrom: Option<String>,
+
+    /// The number of threads to use where work can run in parallel
+    #[clap(long, default_value = "1")]
+    threads: usize,
+
+    /// A command to pipe the bedgraph through before it is written, e.g. "zstd -T0"
+    /// The command reads from stdin and its stdout goes to the output file (or stdout)
+    #[clap(long, conflicts_with = "split_by_chrom")]
+    compress_cmd: Option<String>,
+
+    /// Gzip-compress the bedgraph output, on --threads threads, as an --output ending in
+    /// `.gz` is without it. The output is a series of gzip members, which gzip tools read
+    /// back as one stream
+    #[clap(long, conflicts_with_all = ["compress_cmd", "split_by_chrom", "emit_index"])]
+    gzip: bool,
+
+    /// The compression level, from 0 to 9 for --gzip (6 by default) and from 1 to 22 for
+    /// zstd output (3 by default). An --output ending in `.zst` is zstd-compressed when
+    /// the tool is built with the zstd feature, as one ending in `.gz` is gzip-compressed
+    #[clap(long, value_parser = clap::value_parser!(u32).range(0..=22))]
+    compression_level: Option<u32>,
+
+    /// Also write `<output>.idx`, a `chrom<TAB>start_offset<TAB>end_offset` line for each
+    /// block of records in the bedgraph output, so one chromosome can be read without
+    /// scanning the whole file
+    #[clap(long, requires = "output", conflicts_with_all = ["compress_cmd", "split_by_chrom"])]
+    emit_index: bool,
+
+    /// Collapse records that are duplicates of each other into one, keeping the value
+    /// chosen by --dedup-keep. All records are held until the input ends, unless
+    /// --assume-sorted lets each set of duplicates go as soon as the input moves past it
+    #[clap(long, value_enum)]
+    dedup_by: Option<DedupBy>,
+
+    /// The value kept for a set of duplicates found by --dedup-by
+    #[clap(long, value_enum, default_value = "max", requires = "dedup_by")]
+    dedup_keep: DedupKeep,
+
+    /// Divide each value by the value of this bedgraph where they overlap, e.g. signal over
+    /// control, cutting records where the control intervals start and end. Parts with no
+    /// control interval, or where the control plus --pseudocount is 0, are left out. The
+    /// control is held in memory and must be sorted and non-overlapping
+    #[clap(long, value_name = "BEDGRAPH")]
+    ratio: Option<String>,
+
+    /// Write log2 of the --ratio
+    #[clap(long, requires = "ratio")]
+    log2: bool,
+
+    /// Added to both the value and the control of --ratio, to avoid dividing by 0
+    #[clap(long, default_value = "0", requires = "ratio")]
+    pseudocount: f64,
+
+    /// Replace each value with the median of a window of W records centred on it, which
+    /// is cut short at the ends of each chromosome. Expects each chromosome in one block
+    #[clap(long, value_name = "W", value_parser = clap::value_parser!(u32).range(1..))]
+    median_smooth: Option<u32>,
+
+    /// Summarise the values in windows of this many bases, starting at 0 on every
+    /// chromosome. Only windows overlapping a record are written
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(Coord).range(1..))]
+    bin_size: Option<Coord>,
+
+    /// Like --bin-size, but writes every window of every chromosome in --chrom-sizes, with 0
+    /// for windows no record overlaps. The last window of a chromosome stops at its end, and
+    /// the mean counts bases without a record as 0
+    #[clap(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(Coord).range(1..),
+        requires = "chrom_sizes",
+        conflicts_with_all = ["bin_size", "split_by_chrom"]
+    )]
+    tile_size: Option<Coord>,
+
+    /// The value reported for each window of --bin-size or --tile-size
+    #[clap(long, value_enum, default_value = "mean")]
+    bin_stat: BinStat,
+
+    /// Only keep intervals with a value at or above --threshold and merge the ones lying
+    /// within --merge-gap bases of each other into a single peak. Requires sorted input
+    #[clap(long, requires = "threshold")]
+    call_peaks: bool,
+
+    /// The minimum value for an interval to be kept by --call-peaks. It can be negative, as
+    /// for log2 ratios or z-scores
+    #[clap(long, requires = "call_peaks", allow_negative_numbers = true)]
+    threshold: Option<f64>,
+
+    /// The largest gap in bases between two intervals that are merged into one region, by
+    /// --call-peaks and by --merge
+    #[clap(long, default_value = "0")]
+    merge_gap: Coord,
+
+    /// Merge runs of abutting records with the same value into one record, after any
+    /// binning or peak calling. --merge-gap lets runs span small gaps between records.
+    /// Records keep their other BED columns only when not merged
+    #[clap(long)]
+    merge: bool,
+
+    /// Let --merge join records whose values differ from the first value of the run by at
+    /// most EPS, writing the length-weighted mean of the run
+    #[clap(long, value_name = "EPS", default_value = "0", requires = "merge")]
+    merge_tolerance: f64,
+
+    /// The value reported for a merged peak
+    #[clap(long, value_enum, default_value = "max", requires = "call_peaks")]
+    peak_stat: PeakStat,
 }
 
+const EXIT_INPUT: i32 = 2;
+const EXIT_PARSE: i32 = 3;
+const EXIT_OUTPUT: i32 = 4;
+const EXIT_UNSORTED: i32 = 5;
+const EXIT_COMPRESSOR: i32 = 6;
+const EXIT_INVALID_OUTPUT: i32 = 7;
+const EXIT_USAGE: i32 = 64;
+
+const EXIT_CODES: &str = "Exit codes:
+  0   Success
+  2   An input file could not be opened or read
+  3   A line of an input file could not be parsed
+  4   The output could not be created or written
+  5   The input is not sorted where it has to be
+  6   The compress command could not be started or failed
+  7   The output failed --verify-output
+  64  Invalid command line usage
+  101 Internal error";
+
+/// Why the tool failed, which decides its exit code
 #[derive(Debug)]
-struct BedRecord {
-    chrom: String,
-    start: u32,
-    end: u32,
-    name: String,
-    score: f64,
-    values: Vec<String>,
-}
-
-struct BedParser {
-    input_file: BufReader<File>,
-}
-
-impl BedParser {
-    fn new(input_file: &str) -> Self {
-        let file = File::open(input_file).expect("Could not open file");
-        let reader = BufReader::new(file);
-        BedParser { input_file: reader }
-    }
-}
-
-impl Iterator for BedParser {
-    type Item = BedRecord;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut line = String::new();
-        self.input_file
-            .read_line(&mut line)
-            .expect("Could not read line");
-        if line.is_empty() {
-            return None;
-        }
-        let fields: Vec<&str> = line.trim().split('\t').collect();
-        let chrom = fields[0].to_string();
-        let start = fields[1].parse::<u32>().expect("Could not parse start");
-        let end = fields[2].parse::<u32>().expect("Could not parse end");
-        let name = fields[3].to_string();
-        let score = fields[4].parse::<f64>().unwrap_or(0.0);
-        let values = fields[5..].iter().map(|x| x.to_string()).collect();
-        Some(BedRecord {
-            chrom,
-            start,
-            end,
-            name,
-            values,
-            score,
-        })
+enum CliError {
+    Convert(Error),
+    Compressor(String),
+    Usage(String),
+}
+
+impl From<Error> for CliError {
+    fn from(error: Error) -> Self {
+        CliError::Convert(error)
     }
 }
 
-struct BedGraphRecord {
-    chrom: String,
-    start: u32,
-    end: u32,
-    value: f64,
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        fn convert_exit_code(error: &Error) -> i32 {
+            match error {
+                Error::Open { .. } | Error::Read(_) => EXIT_INPUT,
+                Error::Parse { .. } => EXIT_PARSE,
+                Error::Create { .. } | Error::Write(_) => EXIT_OUTPUT,
+                Error::Unsorted { .. } => EXIT_UNSORTED,
+                Error::InvalidOutput { .. } => EXIT_INVALID_OUTPUT,
+                Error::Manifest { source, .. } => convert_exit_code(source),
+            }
+        }
+        match self {
+            CliError::Convert(error) => convert_exit_code(error),
+            CliError::Compressor(_) => EXIT_COMPRESSOR,
+            CliError::Usage(_) => EXIT_USAGE,
+        }
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Convert(error) => write!(f, "{}", error),
+            CliError::Compressor(message) | CliError::Usage(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+fn open_file(path: &str) -> Result<File, Error> {
+    File::open(path).map_err(|source| Error::Open {
+        path: path.to_string(),
+        source,
+    })
+}
+
+/// Creates the output file, refusing to replace an existing one unless `force` is set.
+/// `create_new` makes the existence check and the creation a single operation
+fn create_output_file(filename: &str, force: bool) -> Result<File, Error> {
+    let file = match force {
+        true => File::create(filename),
+        false => OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(filename),
+    };
+    file.map_err(|source| Error::Create {
+        path: filename.to_string(),
+        source: match source.kind() {
+            std::io::ErrorKind::AlreadyExists => std::io::Error::new(
+                source.kind(),
+                "it already exists, use --force to overwrite it",
+            ),
+            _ => source,
+        },
+    })
+}
+
+/// Writes to a file and copies everything to stdout for --tee, until stdout is closed
+struct Tee<W: Write> {
+    file: W,
+    stdout: Option<BufWriter<std::io::Stdout>>,
 }
 
-struct BedGraphWriter<W: Write> {
-    writer: W,
+impl<W: Write> Tee<W> {
+    /// Stops copying to stdout when it is closed, passing on any other error
+    fn copy_to_stdout(
+        &mut self,
+        op: impl Fn(&mut BufWriter<std::io::Stdout>) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let Some(stdout) = self.stdout.as_mut() else {
+            return Ok(());
+        };
+        match op(stdout) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                self.stdout = None;
+                Ok(())
+            }
+            result => result,
+        }
+    }
 }
 
-impl<W: Write> BedGraphWriter<W> {
-    fn new(writer: W) -> Self {
-        let mut writer = writer;
-        writer
-            .write_all(b"track type=bedGraph\n")
-            .expect("Could not write header");
-        BedGraphWriter { writer }
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write_all(buf)?;
+        self.copy_to_stdout(|stdout| stdout.write_all(buf))?;
+        Ok(buf.len())
     }
 
-    fn write(&mut self, record: &BedGraphRecord) -> std::io::Result<()> {
-        writeln!(
-            self.writer,
-            "{}\t{}\t{}\t{}",
-            record.chrom, record.start, record.end, record.value
-        )
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        self.copy_to_stdout(|stdout| stdout.flush())
     }
 }
 
-fn create_bedgraph_writer(
+/// Opens the output file for --append, creating it when it does not exist yet
+fn append_output_file(filename: &str) -> Result<File, Error> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(filename)
+        .map_err(|source| Error::Create {
+            path: filename.to_string(),
+            source,
+        })
+}
+
+/// Whether --append adds to a file that already has something in it, and so already has
+/// any header it is going to get
+fn appends_to_track(args: &Cli) -> bool {
+    args.append
+        && args
+            .output
+            .as_deref()
+            .and_then(|output| std::fs::metadata(output).ok())
+            .is_some_and(|metadata| metadata.len() > 0)
+}
+
+fn create_output_writer(
+    args: &Cli,
+    compressor: Option<&mut Child>,
+) -> Result<Box<dyn Write>, Error> {
+    let writer: Box<dyn Write> = match (
+        compressor.and_then(|child| child.stdin.take()),
+        args.output.as_deref(),
+    ) {
+        (Some(stdin), _) => Box::new(BufWriter::new(stdin)),
+        (None, Some(filename)) => {
+            let file = match args.append {
+                true => append_output_file(filename)?,
+                false => create_output_file(filename, args.force)?,
+            };
+            let file = BufWriter::new(file);
+            match args.tee {
+                true => Box::new(Tee {
+                    file,
+                    stdout: Some(BufWriter::new(std::io::stdout())),
+                }),
+                false => Box::new(file),
+            }
+        }
+        (None, None) => Box::new(BufWriter::new(std::io::stdout())),
+    };
+    Ok(writer)
+}
+
+/// Starts the compress command with a piped stdin. Its stdout is sent to the output file
+/// if one is given, otherwise it shares our stdout
+fn spawn_compressor(
+    command: &str,
     output_file: Option<&str>,
-) -> std::io::Result<BedGraphWriter<Box<dyn Write>>> {
-    let writer: Box<dyn Write> = match output_file {
-        Some(filename) => Box::new(BufWriter::new(File::create(filename)?)),
-        None => Box::new(BufWriter::new(std::io::stdout())),
+    force: bool,
+) -> Result<Child, CliError> {
+    let mut parts = command.split_whitespace();
+    let program = parts
+        .next()
+        .ok_or_else(|| CliError::Usage("Compress command is empty".to_string()))?;
+    let stdout = match output_file {
+        Some(filename) => Stdio::from(create_output_file(filename, force)?),
+        None => Stdio::inherit(),
     };
-    Ok(BedGraphWriter::new(writer))
+    Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .stdout(stdout)
+        .spawn()
+        .map_err(|e| {
+            // The command never got to write to the file, so don't leave it empty behind
+            if let Some(filename) = output_file {
+                let _ = std::fs::remove_file(filename);
+            }
+            CliError::Compressor(format!("Could not start compress command: {}", e))
+        })
 }
 
-fn main() {
-    let args = Cli::parse();
+/// Reads the header template, warning when it does not look like a bedGraph track line
+fn read_header_file(path: &str) -> Result<String, Error> {
+    let header = std::fs::read_to_string(path).map_err(|source| Error::Open {
+        path: path.to_string(),
+        source,
+    })?;
+    if !header.starts_with("track") || !header.contains("type=bedGraph") {
+        eprintln!(
+            "Warning: {} does not start with `track` and contain `type=bedGraph`",
+            path
+        );
+    }
+    Ok(header)
+}
 
-    let parser = BedParser::new(&args.input);
+struct ManifestEntry {
+    line: usize,
+    path: String,
+    scale: f64,
+    file: File,
+}
 
-    let mut writer =
-        create_bedgraph_writer(args.output.as_deref()).expect("Could not create writer");
+/// Reads the manifest and opens every file in it, so that a missing one is found before
+/// any output is written
+fn read_manifest(manifest: &str) -> Result<Vec<ManifestEntry>, Error> {
+    let mut entries = Vec::new();
+    for (index, line) in BufReader::new(open_file(manifest)?).lines().enumerate() {
+        let line = line.map_err(Error::Read)?;
+        let line_number = index + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (path, scale) = line
+            .trim_end()
+            .split_once('\t')
+            .and_then(|(path, scale)| Some((path, scale.trim().parse::<f64>().ok()?)))
+            .ok_or_else(|| Error::Parse {
+                line: line_number,
+                message: "Expected `path<TAB>scale` in manifest".to_string(),
+            })?;
+        let file = open_file(path).map_err(|source| Error::Manifest {
+            line: line_number,
+            path: path.to_string(),
+            source: Box::new(source),
+        })?;
+        entries.push(ManifestEntry {
+            line: line_number,
+            path: path.to_string(),
+            scale,
+            file,
+        });
+    }
+    Ok(entries)
+}
 
-    let index_to_parse = match args.value_column.as_str() {
-        "score" => -1,
-        _ => args
-            .value_column
-            .parse::<i32>()
-            .expect("Could not parse column index"),
+/// Adds every file in the manifest to the converter, stopping at the first error
+fn add_manifest<O: RecordWriter>(
+    converter: &mut Converter<O>,
+    entries: Vec<ManifestEntry>,
+) -> Result<(), Error> {
+    for entry in entries {
+        converter
+            .add_input(BufReader::new(entry.file), entry.scale)
+            .map_err(|source| Error::Manifest {
+                line: entry.line,
+                path: entry.path,
+                source: Box::new(source),
+            })?;
+    }
+    Ok(())
+}
+
+/// The input file or the files of a manifest, opened before any output is created
+enum Input {
+    File(Box<dyn BufRead>),
+    Manifest(Vec<ManifestEntry>),
+}
+
+impl Input {
+    fn open(args: &Cli) -> Result<Self, CliError> {
+        match (&args.input, &args.manifest) {
+            (_, Some(manifest)) => Ok(Input::Manifest(read_manifest(manifest)?)),
+            (Some(input), None) if input == "-" => {
+                Ok(Input::File(Box::new(BufReader::new(std::io::stdin()))))
+            }
+            (Some(input), None) => Ok(Input::File(Box::new(BufReader::new(open_file(input)?)))),
+            (None, None) => unreachable!("clap requires --input or --manifest"),
+        }
+    }
+}
+
+/// Feeds the input to the converter, handing back the writer once it is done
+fn convert_inputs<O: RecordWriter>(
+    input: Input,
+    mut converter: Converter<O>,
+) -> Result<(ConvertStats, O), Error> {
+    match input {
+        Input::File(reader) => converter.add_input(reader, 1.0)?,
+        Input::Manifest(entries) => add_manifest(&mut converter, entries)?,
+    }
+    converter.finish_into_writer()
+}
+
+fn write_bigwig(
+    args: &Cli,
+    chrom_sizes: Option<ChromSizes>,
+    input: Input,
+    opts: &ConvertOptions,
+) -> Result<ConvertStats, CliError> {
+    let chrom_sizes = chrom_sizes.expect("clap requires --chrom-sizes for bigWig output");
+    let output = args
+        .output
+        .as_deref()
+        .expect("clap requires --output for bigWig output");
+    let file = create_output_file(output, args.force)?;
+    let result = BigWigWriter::new(BufWriter::new(file), chrom_sizes)
+        .map_err(Error::Write)
+        .and_then(|writer| convert_inputs(input, Converter::with_writer(writer, opts)));
+    match result {
+        Ok((stats, _)) => Ok(stats),
+        Err(e) => {
+            // The header is only filled in at the end, so a failed file is of no use and
+            // would block a rerun
+            let _ = std::fs::remove_file(output);
+            Err(e.into())
+        }
+    }
+}
+
+fn write_split(
+    args: &Cli,
+    dir: &str,
+    input: Input,
+    opts: &ConvertOptions,
+) -> Result<ConvertStats, CliError> {
+    std::fs::create_dir_all(dir).map_err(|source| Error::Create {
+        path: dir.to_string(),
+        source,
+    })?;
+    let create_writer = |chrom: &str| {
+        // Keep odd chromosome names from escaping the output directory
+        let filename = format!("{}.bedgraph", chrom.replace(['/', '\\'], "_"));
+        let path = Path::new(dir).join(filename);
+        create_output_file(&path.to_string_lossy(), args.force).map(BufWriter::new)
+    };
+    let results = match input {
+        Input::File(reader) => convert_split(reader, create_writer, args.threads, opts)?,
+        Input::Manifest(_) => unreachable!("clap requires --input with --split-by-chrom"),
     };
+    Ok(results
+        .into_iter()
+        .fold(ConvertStats::default(), |total, (_, stats)| ConvertStats {
+            records: total.records + stats.records,
+            bases: total.bases + stats.bases,
+            clamped: total.clamped + stats.clamped,
+            emptied: total.emptied + stats.emptied,
+            collapsed: total.collapsed + stats.collapsed,
+            zscore: None,
+            summary: match (total.summary, &stats.summary) {
+                (Some(mut total), Some(summary)) => {
+                    total.extend(summary);
+                    Some(total)
+                }
+                (total, summary) => total.or_else(|| summary.clone()),
+            },
+        }))
+}
 
-    let mut has_parsed_first_line = false;
+/// How the bedgraph output is compressed by the tool itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Zstd,
+}
 
-    for record in parser {
-        if !has_parsed_first_line {
-            if index_to_parse > 0 && index_to_parse >= record.values.len() as i32 {
-                eprintln!(
-                    "Could not find column index {} in record. Remember that the index is 0-based and the first value is after the score column",
-                    index_to_parse
-                );
-                break;
+/// The compression an output file's extension asks for, `.gz` for gzip and `.zst` for
+/// zstd, as with the input
+fn extension_compression(output: &str) -> Option<Compression> {
+    if output.ends_with(".gz") {
+        Some(Compression::Gzip)
+    } else if output.ends_with(".zst") {
+        Some(Compression::Zstd)
+    } else {
+        None
+    }
+}
+
+/// Compression follows the extension of --output, and --gzip asks for gzip where there is
+/// no extension to go by, like stdout. A compress command takes over compression
+/// entirely, and bigWig output is never compressed this way
+fn output_compression(args: &Cli) -> Option<Compression> {
+    if args.compress_cmd.is_some() || args.output_format == OutputFormat::Bigwig {
+        return None;
+    }
+    let extension = args.output.as_deref().and_then(extension_compression);
+    match (args.gzip, extension) {
+        (true, _) => Some(Compression::Gzip),
+        (false, compression) => compression,
+    }
+}
+
+fn write_bedgraph(
+    args: &Cli,
+    input: Input,
+    opts: &ConvertOptions,
+) -> Result<ConvertStats, CliError> {
+    let mut compressor = args
+        .compress_cmd
+        .as_deref()
+        .map(|command| spawn_compressor(command, args.output.as_deref(), args.force))
+        .transpose()?;
+
+    let mut writer = create_output_writer(args, compressor.as_mut())?;
+    match output_compression(args) {
+        Some(Compression::Gzip) => {
+            writer = Box::new(ParallelGzEncoder::new(
+                writer,
+                args.compression_level.unwrap_or(6),
+                args.threads,
+            ));
+        }
+        #[cfg(feature = "zstd")]
+        Some(Compression::Zstd) => {
+            let level = args.compression_level.unwrap_or(3) as i32;
+            // Finished by the converter's flush at the end, so that errors there are not lost
+            writer = Box::new(ZstdEncoder::new(writer, level).map_err(Error::Write)?);
+        }
+        #[cfg(not(feature = "zstd"))]
+        Some(Compression::Zstd) => unreachable!("checked before any output is created"),
+        None => {}
+    }
+
+    let normalized = args
+        .output_normalized
+        .as_deref()
+        .map(|path| {
+            let file = create_output_file(path, args.force)?;
+            let mut writer = BedGraphWriter::with_header(
+                BufWriter::new(file),
+                opts.value_format,
+                opts.header.as_deref(),
+            )
+            .map_err(Error::Write)?;
+            writer.set_bed_columns(opts.bed_columns);
+            writer.set_pretty(opts.pretty);
+            Ok::<_, Error>(writer)
+        })
+        .transpose()?;
+
+    // The writer is dropped when the conversion returns, which closes the compressor's stdin
+    let result = match args.output_format {
+        OutputFormat::Lengths => {
+            let writer = LengthsWriter::new(writer, opts.value_format);
+            convert_inputs(input, Converter::with_writer(writer, opts))
+                .map(|(stats, _)| (stats, None))
+        }
+        _ => Converter::new(writer, opts)
+            .and_then(|mut converter| {
+                if let Some(normalized) = normalized {
+                    converter.set_normalized_writer(normalized);
+                }
+                convert_inputs(input, converter)
+            })
+            .map(|(stats, writer)| (stats, Some(writer.index().clone()))),
+    };
+
+    if let Some(mut child) = compressor {
+        let status = child.wait().map_err(|e| {
+            CliError::Compressor(format!("Could not wait for compress command: {}", e))
+        })?;
+        if !status.success() {
+            // What the command wrote is not a complete output, and would block a rerun
+            if let Some(output) = args.output.as_deref() {
+                let _ = std::fs::remove_file(output);
             }
+            // A failing compressor is the root cause of any broken pipe on our side
+            return Err(CliError::Compressor(format!(
+                "Compress command failed: {}",
+                status
+            )));
         }
+        if let Err(Error::Write(e)) = &result {
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                return Err(CliError::Compressor(
+                    "Compress command exited before reading all of the output".to_string(),
+                ));
+            }
+        }
+    }
 
-        let value = match index_to_parse {
-            -1 => record.score,
-            _ => record.values[index_to_parse as usize]
-                .parse::<f64>()
-                .expect("Could not parse value"),
-        };
-        let bg_record = BedGraphRecord {
-            chrom: record.chrom,
-            start: record.start,
-            end: record.end,
-            value,
-        };
-        writer.write(&bg_record).expect("Could not write record");
+    let (stats, index) = result?;
+    if let (true, Some(output), Some(index)) = (args.emit_index, args.output.as_deref(), index) {
+        let path = format!("{}.idx", output);
+        let file = create_output_file(&path, args.force)?;
+        index.write(BufWriter::new(file)).map_err(Error::Write)?;
+    }
+    Ok(stats)
+}
+
+/// Prints the plan of the conversion for --explain, from reading the input to writing
+/// the output
+fn explain(args: &Cli, opts: &ConvertOptions) {
+    let input = match (&args.input, &args.manifest) {
+        (_, Some(manifest)) => format!(
+            "Read each input listed in {}, multiplying its values by its scale",
+            manifest
+        ),
+        (Some(input), None) if input == "-" => "Read stdin".to_string(),
+        (Some(input), None) => format!("Read {}", input),
+        (None, None) => unreachable!("clap requires --input or --manifest"),
+    };
+    let destination = args.output.as_deref().unwrap_or("stdout");
+    let mut output = match (&args.split_by_chrom, args.output_format) {
+        (Some(dir), _) => format!("Write a bedgraph file for each chromosome into {}", dir),
+        (None, format) => format!(
+            "{} {} output to {}",
+            if appends_to_track(args) {
+                "Append"
+            } else {
+                "Write"
+            },
+            format
+                .to_possible_value()
+                .expect("no skipped values")
+                .get_name(),
+            destination
+        ),
+    };
+    match (output_compression(args), &args.compress_cmd) {
+        (Some(Compression::Gzip), _) => output.push_str(", gzip-compressed"),
+        (Some(Compression::Zstd), _) => output.push_str(", zstd-compressed"),
+        (None, Some(command)) => output.push_str(&format!(", piped through `{}`", command)),
+        (None, None) => {}
+    }
+    if args.tee {
+        output.push_str(", copied to stdout");
+    }
+    if let Some(normalized) = &args.output_normalized {
+        output.push_str(&format!(", with the z-scored track in {}", normalized));
+    }
+    if args.emit_index {
+        output.push_str(&format!(", indexed in {}.idx", destination));
+    }
+
+    let steps = std::iter::once(input)
+        .chain(opts.plan())
+        .chain(std::iter::once(output));
+    for (number, step) in steps.enumerate() {
+        println!("{}. {}", number + 1, step);
+    }
+}
 
-        if !has_parsed_first_line {
-            has_parsed_first_line = true;
+fn run(args: &Cli) -> Result<ConvertStats, CliError> {
+    let value = match (&args.value_from_name, args.value_column.as_str()) {
+        (Some(key), _) => ValueSource::NameAttribute(key.clone()),
+        (None, "score") => ValueSource::Score,
+        (None, column) => ValueSource::Column(column.parse::<usize>().map_err(|_| {
+            CliError::Usage(format!(
+                "Could not parse column index '{}', expected 'score' or a number",
+                column
+            ))
+        })?),
+    };
+
+    let chrom_sizes = args
+        .chrom_sizes
+        .as_deref()
+        .map(|path| ChromSizes::read(BufReader::new(open_file(path)?)))
+        .transpose()?;
+
+    let bins = match (args.bin_size, args.tile_size) {
+        (Some(size), _) => Some(BinOptions {
+            size,
+            stat: args.bin_stat,
+            tiles: None,
+        }),
+        (None, Some(size)) => Some(BinOptions {
+            size,
+            stat: args.bin_stat,
+            tiles: chrom_sizes.clone(),
+        }),
+        (None, None) => None,
+    };
+
+    let peaks = args
+        .threshold
+        .filter(|_| args.call_peaks)
+        .map(|threshold| PeakOptions {
+            threshold,
+            merge_gap: args.merge_gap,
+            stat: args.peak_stat,
+        });
+
+    let value_format = match (args.lossless, args.sig_figs) {
+        (true, _) => ValueFormat::Lossless,
+        (false, Some(digits)) => ValueFormat::SigFigs(digits),
+        (false, None) => ValueFormat::default(),
+    };
+
+    let bed_columns = args.output_format == OutputFormat::Bed;
+    let no_header = args.no_header
+        || appends_to_track(args)
+        || matches!(
+            args.output_format,
+            OutputFormat::Bed | OutputFormat::Lengths
+        );
+    let header = match (no_header, &args.header_file) {
+        (true, _) => None,
+        (false, Some(path)) => Some(read_header_file(path)?),
+        (false, None) => Some(DEFAULT_HEADER.to_string()),
+    };
+
+    let chroms = match (
+        args.chrom.is_empty() && args.chrom_regex.is_empty(),
+        &args.chrom_list,
+    ) {
+        (true, None) => None,
+        (_, list) => {
+            let mut chroms = ChromFilter::new(args.chrom.clone(), args.chrom_regex.clone());
+            if let Some(list) = list {
+                chroms.read_names(BufReader::new(open_file(list)?))?;
+            }
+            Some(chroms)
+        }
+    };
+
+    if args.quantize.iter().any(|level| !level.is_finite()) {
+        return Err(CliError::Usage(
+            "Could not use --quantize levels that are not finite numbers".to_string(),
+        ));
+    }
+    let quantize = (!args.quantize.is_empty())
+        .then(|| QuantizeOptions::new(args.quantize.clone(), args.quantize_mode));
+
+    let ratio = match &args.ratio {
+        Some(path) => Some(RatioOptions {
+            control: Arc::new(Track::read(BufReader::new(open_file(path)?))?),
+            pseudocount: args.pseudocount,
+            log2: args.log2,
+        }),
+        None => None,
+    };
+
+    // bigWig output can only hold sorted records within the chromosomes it was given, which
+    // are checked on the input so that errors point at its lines
+    let bigwig = args.output_format == OutputFormat::Bigwig;
+    let opts = ConvertOptions {
+        value,
+        merge_tolerance: args.merge.then_some(args.merge_tolerance),
+        merge_gap: args.merge_gap,
+        quantize,
+        binarize: args.binarize,
+        binarize_drop: args.binarize_drop,
+        default_score: args.default_score,
+        chrom_prefix: args.chrom_prefix,
+        chroms,
+        chrom_sizes: chrom_sizes.clone().filter(|_| bigwig),
+        header,
+        bed_columns,
+        pretty: args.pretty,
+        dedup: args.dedup_by.map(|by| DedupOptions {
+            by,
+            keep: args.dedup_keep,
+        }),
+        ratio,
+        median_window: args.median_smooth.map(|window| window as usize),
+        bins,
+        peaks,
+        value_format,
+        scale: args.scale,
+        offset: args.offset,
+        offset_order: args.offset_order,
+        shift: args.shift,
+        extend: args.extend,
+        transform: args.transform.clone(),
+        zscore: args.zscore,
+        summary: args.stats,
+        verify_output: args.verify_output,
+        keep_comments: args.keep_comments,
+        check_sorted: args.check_sorted || bigwig,
+        assume_sorted: args.assume_sorted,
+        regroup: args.regroup,
+        head: args.head,
+        tail: args.tail,
+        missing_column: args.missing_column_action,
+    };
+
+    #[cfg(not(feature = "zstd"))]
+    if output_compression(args) == Some(Compression::Zstd) {
+        return Err(CliError::Usage(
+            "Could not write zstd output, this build does not have the zstd feature".to_string(),
+        ));
+    }
+    if args.gzip
+        && args.output.as_deref().and_then(extension_compression) == Some(Compression::Zstd)
+    {
+        return Err(CliError::Usage(
+            "--gzip does not go with an --output ending in `.zst`".to_string(),
+        ));
+    }
+    match (output_compression(args), args.compression_level) {
+        (Some(Compression::Gzip), Some(level)) if level > 9 => {
+            return Err(CliError::Usage(format!(
+                "Could not use compression level {} for gzip, expected 0 to 9",
+                level
+            )))
+        }
+        (Some(Compression::Zstd), Some(0)) => {
+            return Err(CliError::Usage(
+                "Could not use compression level 0 for zstd, expected 1 to 22".to_string(),
+            ))
+        }
+        (Some(_), _) if args.emit_index => {
+            return Err(CliError::Usage(
+                "--emit-index only works with uncompressed output".to_string(),
+            ))
+        }
+        _ => {}
+    }
+
+    // Flags that only work with the bedgraph writer, or with a plain output stream
+    let unsupported: &[(bool, &str)] = match args.output_format {
+        OutputFormat::Bedgraph | OutputFormat::Bed => &[],
+        OutputFormat::Bigwig => &[
+            (args.emit_index, "--emit-index"),
+            (args.compress_cmd.is_some(), "--compress-cmd"),
+            (args.gzip, "--gzip"),
+            (args.tee, "--tee"),
+            (args.append, "--append"),
+            (args.output_normalized.is_some(), "--output-normalized"),
+        ],
+        OutputFormat::Lengths => &[
+            (args.emit_index, "--emit-index"),
+            (args.output_normalized.is_some(), "--output-normalized"),
+        ],
+    };
+    if let Some((_, flag)) = unsupported.iter().find(|(given, _)| *given) {
+        return Err(CliError::Usage(format!(
+            "{} only works with bedgraph output",
+            flag
+        )));
+    }
+
+    if args.explain {
+        explain(args, &opts);
+        return Ok(ConvertStats::default());
+    }
+
+    let input = Input::open(args)?;
+
+    let stats = match (&args.split_by_chrom, args.output_format) {
+        (Some(dir), _) => write_split(args, dir, input, &opts)?,
+        (None, OutputFormat::Bedgraph | OutputFormat::Bed | OutputFormat::Lengths) => {
+            write_bedgraph(args, input, &opts)?
         }
+        (None, OutputFormat::Bigwig) => write_bigwig(args, chrom_sizes, input, &opts)?,
+    };
+
+    if let Some(summary) = &stats.summary {
+        let result = match &args.stats_output {
+            Some(path) => summary.write(
+                BufWriter::new(create_output_file(path, args.force)?),
+                args.stats_format,
+            ),
+            None => summary.write(std::io::stderr().lock(), args.stats_format),
+        };
+        result.map_err(Error::Write)?;
     }
+    Ok(stats)
+}
 
-    // the value_column
+fn main() {
+    let args = Cli::try_parse().unwrap_or_else(|e| {
+        // --help and --version also end up here, on stdout
+        let _ = e.print();
+        std::process::exit(if e.use_stderr() { EXIT_USAGE } else { 0 });
+    });
+
+    match run(&args) {
+        Ok(_) if args.explain => {}
+        Ok(stats) => {
+            if let Some(moments) = stats.zscore {
+                eprintln!(
+                    "Z-scored against mean {} and stddev {}",
+                    moments.mean, moments.stddev
+                );
+            }
+            if args.dedup_by.is_some() {
+                eprintln!(
+                    "Collapsed {} duplicate records into others",
+                    stats.collapsed
+                );
+            }
+            if stats.clamped > 0 {
+                eprintln!(
+                    "Warning: the start of {} records was held at 0 by --shift or --extend",
+                    stats.clamped
+                );
+            }
+            if stats.emptied > 0 {
+                eprintln!(
+                    "Warning: {} records were shifted entirely below 0 and dropped",
+                    stats.emptied
+                );
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code());
+        }
+    }
 }
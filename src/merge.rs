@@ -0,0 +1,133 @@
+use crate::{bases, BedGraphRecord, Coord, Error, Stage};
+
+/// A run of abutting records being merged into one
+struct Run {
+    record: BedGraphRecord,
+    /// The value of the first record, which the others are compared against
+    first: f64,
+    weighted_sum: f64,
+    bases: u64,
+    all_equal: bool,
+}
+
+impl Run {
+    fn new(record: BedGraphRecord) -> Self {
+        let bases = bases(record.start, record.end);
+        Run {
+            first: record.value,
+            weighted_sum: record.value * bases as f64,
+            bases,
+            all_equal: true,
+            record,
+        }
+    }
+
+    /// The length-weighted mean of the run. Equal values are passed on as they are, as
+    /// the mean need not come out as exactly the same number
+    fn into_record(self) -> BedGraphRecord {
+        let mut record = self.record;
+        record.value = match self.all_equal || self.bases == 0 {
+            true => self.first,
+            false => self.weighted_sum / self.bases as f64,
+        };
+        record
+    }
+}
+
+/// Merges runs of records on the same chromosome whose values are all within `tolerance`
+/// of the first value of the run, and that each start at most `gap` bases after the one
+/// before ends. The merged record spans the gaps, which do not count towards its mean
+pub(crate) struct Merger {
+    tolerance: f64,
+    gap: Coord,
+    current: Option<Run>,
+}
+
+impl Merger {
+    pub(crate) fn new(tolerance: f64, gap: Coord) -> Self {
+        Merger {
+            tolerance,
+            gap,
+            current: None,
+        }
+    }
+}
+
+impl Stage for Merger {
+    fn push(&mut self, record: BedGraphRecord, out: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        match self.current.as_mut() {
+            Some(run)
+                if run.record.chrom == record.chrom
+                    && (run.record.end..=run.record.end.saturating_add(self.gap))
+                        .contains(&record.start)
+                    && (record.value - run.first).abs() <= self.tolerance =>
+            {
+                let bases = bases(record.start, record.end);
+                run.record.end = record.end;
+                // The columns of one record do not describe the merged run
+                run.record.columns = None;
+                run.weighted_sum += record.value * bases as f64;
+                run.bases += bases;
+                run.all_equal &= record.value == run.first;
+            }
+            _ => {
+                if let Some(run) = self.current.replace(Run::new(record)) {
+                    out.push(run.into_record());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        if let Some(run) = self.current.take() {
+            out.push(run.into_record());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{record, spans};
+
+    /// Merges `c:0-10` with `second`, giving the coordinates and values that come out
+    fn merge(tolerance: f64, gap: Coord, second: BedGraphRecord) -> Vec<(Coord, Coord, f64)> {
+        let mut merger = Merger::new(tolerance, gap);
+        let mut out = Vec::new();
+        merger.push(record("c", 0, 10, 1.0), &mut out).unwrap();
+        merger.push(second, &mut out).unwrap();
+        merger.finish(&mut out).unwrap();
+        spans(&out)
+    }
+
+    /// Merges `c:0-10` with an equal record that starts `distance` bases after it ends
+    fn merge_equal(gap: Coord, distance: Coord) -> Vec<(Coord, Coord, f64)> {
+        let start = 10 + distance;
+        merge(0.0, gap, record("c", start, start + 10, 1.0))
+    }
+
+    #[test]
+    fn abutting_records_merge_without_a_gap() {
+        assert_eq!(merge_equal(0, 0), vec![(0, 20, 1.0)]);
+        assert_eq!(merge_equal(0, 1), vec![(0, 10, 1.0), (11, 21, 1.0)]);
+    }
+
+    #[test]
+    fn records_merge_across_gaps_up_to_the_limit() {
+        assert_eq!(merge_equal(4, 0), vec![(0, 20, 1.0)]);
+        assert_eq!(merge_equal(4, 1), vec![(0, 21, 1.0)]);
+        assert_eq!(merge_equal(4, 4), vec![(0, 24, 1.0)]);
+        assert_eq!(merge_equal(4, 5), vec![(0, 10, 1.0), (15, 25, 1.0)]);
+    }
+
+    #[test]
+    fn values_within_the_tolerance_merge_into_their_mean() {
+        assert_eq!(
+            merge(0.0, 0, record("c", 10, 20, 3.0)),
+            vec![(0, 10, 1.0), (10, 20, 3.0)]
+        );
+        assert_eq!(merge(5.0, 0, record("c", 10, 20, 3.0)), vec![(0, 20, 2.0)]);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{BedGraphRecord, BedRecord, Coord, Error, Stage};
+use std::collections::{HashMap, HashSet};
+
+/// Checks that records come in contiguous chromosome blocks with non-decreasing starts
+/// within each chromosome
+pub(crate) struct SortChecker {
+    /// Interleaved blocks are not an error when they are regrouped afterwards
+    allow_interleaved: bool,
+    current_chrom: Option<String>,
+    last_starts: HashMap<String, Coord>,
+}
+
+impl SortChecker {
+    pub(crate) fn new(allow_interleaved: bool) -> Self {
+        SortChecker {
+            allow_interleaved,
+            current_chrom: None,
+            last_starts: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn check(&mut self, record: &BedRecord, line: usize) -> Result<(), Error> {
+        if self.current_chrom.as_deref() != Some(record.chrom.as_str()) {
+            if !self.allow_interleaved && self.last_starts.contains_key(&record.chrom) {
+                return Err(Error::unsorted(
+                    line,
+                    format!(
+                        "Interleaved chromosome {}: its records were already interrupted by {}. Use --regroup to reunite its blocks",
+                        record.chrom,
+                        self.current_chrom.as_deref().unwrap_or_default()
+                    ),
+                ));
+            }
+            self.current_chrom = Some(record.chrom.clone());
+        }
+        let last_start = self
+            .last_starts
+            .entry(record.chrom.clone())
+            .or_insert(record.start);
+        if record.start < *last_start {
+            return Err(Error::unsorted(
+                line,
+                format!(
+                    "Start {} on {} comes after start {}",
+                    record.start, record.chrom, last_start
+                ),
+            ));
+        }
+        *last_start = record.start;
+        Ok(())
+    }
+}
+
+/// Buffers records per chromosome so blocks of a chromosome that are split up in the
+/// input come out together, in the order each chromosome was first seen
+#[derive(Default)]
+pub(crate) struct Regrouper {
+    chroms: HashMap<String, usize>,
+    blocks: Vec<Vec<BedGraphRecord>>,
+}
+
+impl Stage for Regrouper {
+    fn push(&mut self, record: BedGraphRecord, _: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        let index = *self.chroms.entry(record.chrom.clone()).or_insert_with(|| {
+            self.blocks.push(Vec::new());
+            self.blocks.len() - 1
+        });
+        self.blocks[index].push(record);
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        self.chroms.clear();
+        out.extend(self.blocks.drain(..).flatten());
+        Ok(())
+    }
+}
+
+/// Checks that the written records are valid bedGraph: contiguous chromosome blocks of
+/// sorted records that do not overlap
+#[derive(Default)]
+pub(crate) struct OutputVerifier {
+    current: Option<(String, Coord)>,
+    finished_chroms: HashSet<String>,
+}
+
+impl OutputVerifier {
+    /// Checks the `number`th written record
+    pub(crate) fn check(&mut self, record: &BedGraphRecord, number: u64) -> Result<(), Error> {
+        let invalid = |message: String| Error::InvalidOutput { number, message };
+        if record.end <= record.start {
+            return Err(invalid(format!(
+                "{}:{}-{} does not end after it starts",
+                record.chrom, record.start, record.end
+            )));
+        }
+        match self.current.as_mut() {
+            Some((chrom, last_end)) if *chrom == record.chrom => {
+                if record.start < *last_end {
+                    return Err(invalid(format!(
+                        "{}:{}-{} starts before the end {} of the record before it",
+                        record.chrom, record.start, record.end, last_end
+                    )));
+                }
+                *last_end = record.end;
+            }
+            _ => {
+                if self.finished_chroms.contains(&record.chrom) {
+                    return Err(invalid(format!(
+                        "{} comes back after other chromosomes",
+                        record.chrom
+                    )));
+                }
+                if let Some((chrom, _)) = self.current.replace((record.chrom.clone(), record.end)) {
+                    self.finished_chroms.insert(chrom);
+                }
+            }
+        }
+        Ok(())
+    }
+}
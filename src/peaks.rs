@@ -0,0 +1,111 @@
+use crate::{bases, BedGraphRecord, Coord, Error, Stage};
+use clap::ValueEnum;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum PeakStat {
+    /// The largest value of the merged intervals
+    Max,
+    /// The mean value of the merged intervals, weighted by their length
+    Mean,
+}
+
+#[derive(Clone, Debug)]
+pub struct PeakOptions {
+    /// The minimum value for an interval to be kept
+    pub threshold: f64,
+    /// The largest gap in bases between two intervals that are merged into one peak
+    pub merge_gap: Coord,
+    /// The value reported for a merged peak
+    pub stat: PeakStat,
+}
+
+struct Peak {
+    chrom: String,
+    start: Coord,
+    end: Coord,
+    max: f64,
+    weighted_sum: f64,
+    bases: u64,
+}
+
+impl Peak {
+    fn new(record: BedGraphRecord) -> Self {
+        let bases = bases(record.start, record.end);
+        Peak {
+            chrom: record.chrom,
+            start: record.start,
+            end: record.end,
+            max: record.value,
+            weighted_sum: record.value * bases as f64,
+            bases,
+        }
+    }
+
+    fn into_record(self, stat: PeakStat) -> BedGraphRecord {
+        let value = match stat {
+            PeakStat::Max => self.max,
+            // Zero-length intervals carry no weight, so fall back to the max
+            PeakStat::Mean if self.bases == 0 => self.max,
+            PeakStat::Mean => self.weighted_sum / self.bases as f64,
+        };
+        BedGraphRecord {
+            chrom: self.chrom,
+            start: self.start,
+            end: self.end,
+            value,
+            columns: None,
+        }
+    }
+}
+
+/// Filters records by a value threshold and merges the survivors that lie within
+/// `merge_gap` bases of each other into a single peak
+pub(crate) struct PeakCaller {
+    options: PeakOptions,
+    current: Option<Peak>,
+}
+
+impl PeakCaller {
+    pub(crate) fn new(options: PeakOptions) -> Self {
+        PeakCaller {
+            options,
+            current: None,
+        }
+    }
+}
+
+impl Stage for PeakCaller {
+    /// Adds a record to the current peak, passing on the previous peak once a record
+    /// starts a new one
+    fn push(&mut self, record: BedGraphRecord, out: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        if record.value < self.options.threshold {
+            return Ok(());
+        }
+        match self.current.as_mut() {
+            Some(peak)
+                if peak.chrom == record.chrom
+                    && record.start <= peak.end.saturating_add(self.options.merge_gap) =>
+            {
+                let bases = bases(record.start, record.end);
+                peak.start = peak.start.min(record.start);
+                peak.end = peak.end.max(record.end);
+                peak.max = peak.max.max(record.value);
+                peak.weighted_sum += record.value * bases as f64;
+                peak.bases += bases;
+            }
+            _ => {
+                if let Some(peak) = self.current.replace(Peak::new(record)) {
+                    out.push(peak.into_record(self.options.stat));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        if let Some(peak) = self.current.take() {
+            out.push(peak.into_record(self.options.stat));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use crate::{ConvertOptions, DedupBy, MissingColumnAction, OffsetOrder, ValueSource};
+use clap::ValueEnum;
+
+/// The name an option value has on the command line
+fn name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string())
+}
+
+impl ConvertOptions {
+    /// Describes each step a record goes through, in the order the converter runs them.
+    /// Steps that the options turn off are left out
+    pub fn plan(&self) -> Vec<String> {
+        let mut steps = Vec::new();
+        steps.push(
+            match self.keep_comments {
+                true => "Copy `#` comment lines to the output, skip track, browser and blank lines",
+                false => "Skip comment, track, browser and blank lines",
+            }
+            .to_string(),
+        );
+        if let Some(prefix) = self.chrom_prefix {
+            steps.push(format!(
+                "{} the `chr` prefix of chromosome names",
+                name(&prefix)
+            ));
+        }
+        if self.chroms.is_some() {
+            steps.push("Keep only the chosen chromosomes".to_string());
+        }
+        if self.check_sorted || self.assume_sorted {
+            steps.push("Check that the input is sorted".to_string());
+        }
+        let missing = match self.missing_column {
+            MissingColumnAction::Skip => "skip records without it",
+            MissingColumnAction::Zero => "use 0 for records without it",
+            MissingColumnAction::Error => "stop at a record without it",
+        };
+        steps.push(match &self.value {
+            ValueSource::Score => format!(
+                "Read the value from the score column, {} when it is missing or not a number",
+                self.default_score
+            ),
+            ValueSource::Column(column) => format!(
+                "Read the value from column {} after the score, and {}",
+                column, missing
+            ),
+            ValueSource::NameAttribute(key) => format!(
+                "Read the value from the `{}` attribute of the name column, and {} or with a value that is not a number",
+                key, missing
+            ),
+        });
+        let scale = (self.scale != 1.0).then(|| format!("Multiply by {}", self.scale));
+        let offset = (self.offset != 0.0).then(|| format!("Add {}", self.offset));
+        let (first, second) = match self.offset_order {
+            OffsetOrder::AfterScale => (scale, offset),
+            OffsetOrder::BeforeScale => (offset, scale),
+        };
+        steps.extend(first.into_iter().chain(second));
+        if self.shift != 0 {
+            steps.push(format!("Shift by {} bases", self.shift));
+        }
+        if self.extend != 0 {
+            steps.push(format!("Extend both sides by {} bases", self.extend));
+        }
+        if self.chrom_sizes.is_some() {
+            steps.push(
+                "Check that each record is on a chromosome of the chrom sizes and ends within it"
+                    .to_string(),
+            );
+        }
+        if self.transform.is_some() {
+            steps.push("Apply the transform expression".to_string());
+        }
+        if let Some(quantize) = &self.quantize {
+            steps.push(format!(
+                "Quantize onto {:?} by {}",
+                quantize.levels,
+                name(&quantize.mode)
+            ));
+        }
+        if let Some(threshold) = self.binarize {
+            steps.push(match self.binarize_drop {
+                true => format!("Drop values below {} and write 1 for the rest", threshold),
+                false => format!(
+                    "Write 1 for values of at least {} and 0 for the rest",
+                    threshold
+                ),
+            });
+        }
+        if let Some(head) = self.head {
+            steps.push(format!("Stop after {} records have made it this far", head));
+        }
+        if let Some(dedup) = &self.dedup {
+            let by = match dedup.by {
+                DedupBy::Coord => "coordinates",
+            };
+            steps.push(format!(
+                "Collapse records with the same {}, keeping the {} value",
+                by,
+                name(&dedup.keep)
+            ));
+        }
+        if let Some(count) = self.tail {
+            steps.push(format!("Keep the last {} records", count));
+        }
+        if self.regroup {
+            steps.push("Regroup the blocks of each chromosome".to_string());
+        }
+        if let Some(ratio) = &self.ratio {
+            steps.push(format!(
+                "Divide by the control track with pseudocount {}{}",
+                ratio.pseudocount,
+                if ratio.log2 { ", then take log2" } else { "" }
+            ));
+        }
+        if let Some(window) = self.median_window {
+            steps.push(format!("Take the median of windows of {} records", window));
+        }
+        if let Some(bins) = &self.bins {
+            steps.push(format!(
+                "Take the {} of {} of {} bases",
+                name(&bins.stat),
+                if bins.tiles.is_some() {
+                    "every tile"
+                } else {
+                    "bins"
+                },
+                bins.size
+            ));
+        }
+        if let Some(peaks) = &self.peaks {
+            steps.push(format!(
+                "Call peaks at or above {}, {} bases apart at most, with their {}",
+                peaks.threshold,
+                peaks.merge_gap,
+                name(&peaks.stat)
+            ));
+        }
+        if let Some(tolerance) = self.merge_tolerance {
+            let values = match tolerance {
+                0.0 => "equal values".to_string(),
+                tolerance => format!("values within {} of the first", tolerance),
+            };
+            let gaps = match self.merge_gap {
+                0 => "abutting records".to_string(),
+                gap => format!("records up to {} bases apart", gap),
+            };
+            steps.push(format!("Merge runs of {} with {}", gaps, values));
+        }
+        if self.zscore {
+            steps.push("Replace values with their z-scores".to_string());
+        }
+        if self.verify_output {
+            steps.push("Check that the output is valid bedGraph".to_string());
+        }
+        if self.summary {
+            steps.push("Collect per-chromosome statistics".to_string());
+        }
+        steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_attribute_steps_follow_the_missing_column_action() {
+        let step = |missing_column| {
+            let opts = ConvertOptions {
+                value: ValueSource::NameAttribute("signal".to_string()),
+                missing_column,
+                ..ConvertOptions::default()
+            };
+            opts.plan()[1].clone()
+        };
+        assert_eq!(
+            step(MissingColumnAction::Skip),
+            "Read the value from the `signal` attribute of the name column, and skip records without it or with a value that is not a number"
+        );
+        assert!(step(MissingColumnAction::Zero).contains("use 0 for records without it"));
+        assert!(step(MissingColumnAction::Error).contains("stop at a record without it"));
+    }
+}
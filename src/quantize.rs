@@ -0,0 +1,50 @@
+use clap::ValueEnum;
+
+/// How a value is mapped onto the quantization levels
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QuantizeMode {
+    /// The closest level, or the lower one of two equally close levels
+    #[default]
+    Nearest,
+    /// The number of levels at or below the value, so the levels are the breakpoints
+    /// between bins 0 to N
+    Bin,
+}
+
+#[derive(Clone, Debug)]
+pub struct QuantizeOptions {
+    /// The levels in ascending order
+    pub levels: Vec<f64>,
+    pub mode: QuantizeMode,
+}
+
+impl QuantizeOptions {
+    /// Sorts the levels and drops repeated ones
+    pub fn new(levels: Vec<f64>, mode: QuantizeMode) -> Self {
+        let mut levels = levels;
+        levels.sort_by(f64::total_cmp);
+        levels.dedup();
+        QuantizeOptions { levels, mode }
+    }
+
+    /// Maps a value onto the levels. NaN stays NaN
+    pub fn apply(&self, value: f64) -> f64 {
+        if value.is_nan() || self.levels.is_empty() {
+            return value;
+        }
+        let above = self.levels.partition_point(|level| *level <= value);
+        match self.mode {
+            QuantizeMode::Bin => above as f64,
+            QuantizeMode::Nearest => {
+                let lower = above.checked_sub(1).map(|i| self.levels[i]);
+                let upper = self.levels.get(above).copied();
+                match (lower, upper) {
+                    (Some(lower), Some(upper)) if upper - value < value - lower => upper,
+                    (Some(lower), _) => lower,
+                    (None, Some(upper)) => upper,
+                    (None, None) => value,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::bed::is_header_line;
+use crate::{BedGraphRecord, Coord, Error, Stage};
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::sync::Arc;
+
+/// The intervals of a bedGraph track, held in memory
+#[derive(Debug, Default)]
+pub struct Track {
+    chroms: HashMap<String, Vec<(Coord, Coord, f64)>>,
+}
+
+impl Track {
+    /// Reads `chrom<TAB>start<TAB>end<TAB>value` lines, skipping comments, `track` and
+    /// `browser` lines. The intervals of a chromosome must be sorted and not overlap
+    pub fn read<R: BufRead>(reader: R) -> Result<Self, Error> {
+        let mut chroms: HashMap<String, Vec<(Coord, Coord, f64)>> = HashMap::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line.map_err(Error::Read)?;
+            if is_header_line(&line) {
+                continue;
+            }
+            let fields: Vec<&str> = line.trim().split('\t').collect();
+            let interval = match fields[..] {
+                [chrom, start, end, value, ..] => (|| {
+                    Some((
+                        chrom,
+                        start.parse::<Coord>().ok()?,
+                        end.parse::<Coord>().ok()?,
+                        value.parse::<f64>().ok()?,
+                    ))
+                })(),
+                _ => None,
+            };
+            let (chrom, start, end, value) = interval.ok_or_else(|| {
+                Error::parse(
+                    index + 1,
+                    "Expected `chrom<TAB>start<TAB>end<TAB>value` in bedGraph",
+                )
+            })?;
+            let intervals = chroms.entry(chrom.to_string()).or_default();
+            if intervals
+                .last()
+                .is_some_and(|&(_, last_end, _)| start < last_end)
+            {
+                return Err(Error::parse(
+                    index + 1,
+                    format!(
+                        "Interval starts before the end of the previous one on {}, the control must be sorted and not overlap",
+                        chrom
+                    ),
+                ));
+            }
+            intervals.push((start, end, value));
+        }
+        Ok(Track { chroms })
+    }
+
+    /// The intervals of `chrom` that overlap `start..end`, in order
+    fn overlapping(&self, chrom: &str, start: Coord, end: Coord) -> &[(Coord, Coord, f64)] {
+        let Some(intervals) = self.chroms.get(chrom) else {
+            return &[];
+        };
+        // Non-overlapping intervals sorted by start are sorted by end as well
+        let first = intervals.partition_point(|&(_, e, _)| e <= start);
+        let last = intervals.partition_point(|&(s, _, _)| s < end);
+        &intervals[first..last.max(first)]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RatioOptions {
+    /// The track every value is divided by
+    pub control: Arc<Track>,
+    /// Added to both sides of the ratio
+    pub pseudocount: f64,
+    /// Write log2 of the ratio
+    pub log2: bool,
+}
+
+/// Divides each record by the control track, cutting it where the control intervals
+/// start and end. Parts without a control interval, or where the control plus the
+/// pseudocount is 0, are left out
+pub(crate) struct Ratio {
+    options: RatioOptions,
+}
+
+impl Ratio {
+    pub(crate) fn new(options: RatioOptions) -> Self {
+        Ratio { options }
+    }
+}
+
+impl Stage for Ratio {
+    fn push(&mut self, record: BedGraphRecord, out: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        let control = &self.options.control;
+        let pseudocount = self.options.pseudocount;
+        for &(start, end, other) in control.overlapping(&record.chrom, record.start, record.end) {
+            let denominator = other + pseudocount;
+            if denominator == 0.0 {
+                continue;
+            }
+            let ratio = (record.value + pseudocount) / denominator;
+            out.push(BedGraphRecord {
+                chrom: record.chrom.clone(),
+                start: start.max(record.start),
+                end: end.min(record.end),
+                value: if self.options.log2 {
+                    ratio.log2()
+                } else {
+                    ratio
+                },
+                columns: record.columns.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, _: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{BedGraphRecord, Error, Stage};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, VecDeque};
+
+/// A value ordered by `f64::total_cmp`, so that it can key a map
+#[derive(Clone, Copy, Debug)]
+struct Value(f64);
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// A multiset of values split into a lower and an upper half, so that adding a value,
+/// removing one and finding the median each take O(log W) for a window of W values.
+/// The lower half has the extra value when there is an odd number of them
+#[derive(Default)]
+struct Halves {
+    /// Each distinct value of a half and how many times it is held
+    lower: BTreeMap<Value, usize>,
+    upper: BTreeMap<Value, usize>,
+    lower_len: usize,
+    upper_len: usize,
+}
+
+impl Halves {
+    fn add(half: &mut BTreeMap<Value, usize>, value: Value) {
+        *half.entry(value).or_insert(0) += 1;
+    }
+
+    /// Takes one copy of `value` out of `half`, saying whether there was one
+    fn take(half: &mut BTreeMap<Value, usize>, value: Value) -> bool {
+        let Some(count) = half.get_mut(&value) else {
+            return false;
+        };
+        *count -= 1;
+        if *count == 0 {
+            half.remove(&value);
+        }
+        true
+    }
+
+    fn largest_lower(&self) -> Option<Value> {
+        self.lower.last_key_value().map(|(&value, _)| value)
+    }
+
+    fn smallest_upper(&self) -> Option<Value> {
+        self.upper.first_key_value().map(|(&value, _)| value)
+    }
+
+    fn insert(&mut self, value: f64) {
+        let value = Value(value);
+        if self.largest_lower().is_none_or(|largest| value <= largest) {
+            Self::add(&mut self.lower, value);
+            self.lower_len += 1;
+        } else {
+            Self::add(&mut self.upper, value);
+            self.upper_len += 1;
+        }
+        self.rebalance();
+    }
+
+    fn remove(&mut self, value: f64) {
+        let value = Value(value);
+        if Self::take(&mut self.lower, value) {
+            self.lower_len -= 1;
+        } else if Self::take(&mut self.upper, value) {
+            self.upper_len -= 1;
+        }
+        self.rebalance();
+    }
+
+    /// Moves the values next to the split across until the lower half has as many values
+    /// as the upper half, or one more
+    fn rebalance(&mut self) {
+        while self.lower_len > self.upper_len + 1 {
+            let value = self.largest_lower().expect("the lower half is not empty");
+            Self::take(&mut self.lower, value);
+            Self::add(&mut self.upper, value);
+            (self.lower_len, self.upper_len) = (self.lower_len - 1, self.upper_len + 1);
+        }
+        while self.upper_len > self.lower_len {
+            let value = self.smallest_upper().expect("the upper half is not empty");
+            Self::take(&mut self.upper, value);
+            Self::add(&mut self.lower, value);
+            (self.lower_len, self.upper_len) = (self.lower_len + 1, self.upper_len - 1);
+        }
+    }
+
+    fn median(&self) -> f64 {
+        let middle = self.largest_lower().expect("the window is not empty").0;
+        match (self.lower_len == self.upper_len, self.smallest_upper()) {
+            (true, Some(next)) => (middle + next.0) / 2.0,
+            _ => middle,
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = Halves::default();
+    }
+}
+
+/// Replaces each value with the median of a window of `window` records centred on it.
+/// The window never reaches into another chromosome, so it is cut short at the ends of
+/// each chromosome block. Only about one window of records is held at a time
+pub(crate) struct MedianSmoother {
+    before: usize,
+    after: usize,
+    /// The records of the current window, ending with the newest one
+    records: VecDeque<BedGraphRecord>,
+    /// The values of `records`
+    values: Halves,
+    /// The position in `records` of the next record to pass on
+    next: usize,
+}
+
+impl MedianSmoother {
+    pub(crate) fn new(window: usize) -> Self {
+        let window = window.max(1);
+        MedianSmoother {
+            before: (window - 1) / 2,
+            after: window / 2,
+            records: VecDeque::new(),
+            values: Halves::default(),
+            next: 0,
+        }
+    }
+
+    /// Passes on the next record with the median of its window, then drops the oldest
+    /// record if it has fallen out of the window of the record after it
+    fn emit_next(&mut self, out: &mut Vec<BedGraphRecord>) {
+        let mut record = self.records[self.next].clone();
+        record.value = self.values.median();
+        out.push(record);
+        self.next += 1;
+        if self.next > self.before {
+            let oldest = self.records.pop_front().expect("the window is not empty");
+            self.values.remove(oldest.value);
+            self.next -= 1;
+        }
+    }
+
+    /// Passes on every record still held, with the window shrinking towards the end
+    fn flush(&mut self, out: &mut Vec<BedGraphRecord>) {
+        while self.next < self.records.len() {
+            self.emit_next(out);
+        }
+        self.records.clear();
+        self.values.clear();
+        self.next = 0;
+    }
+}
+
+impl Stage for MedianSmoother {
+    fn push(&mut self, record: BedGraphRecord, out: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        if self
+            .records
+            .back()
+            .is_some_and(|last| last.chrom != record.chrom)
+        {
+            self.flush(out);
+        }
+        self.values.insert(record.value);
+        self.records.push_back(record);
+        if self.records.len() > self.next + self.after {
+            self.emit_next(out);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        self.flush(out);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::record;
+    use crate::Coord;
+
+    fn values(window: usize, records: &[(&str, f64)]) -> Vec<f64> {
+        let mut smoother = MedianSmoother::new(window);
+        let mut out = Vec::new();
+        for (index, &(chrom, value)) in records.iter().enumerate() {
+            let start = index as Coord * 10;
+            smoother
+                .push(record(chrom, start, start + 10, value), &mut out)
+                .unwrap();
+        }
+        smoother.finish(&mut out).unwrap();
+        out.iter().map(|record| record.value).collect()
+    }
+
+    #[test]
+    fn windows_stop_at_chromosome_ends() {
+        let records = [("a", 1.0), ("a", 5.0), ("a", 3.0), ("b", 9.0), ("b", 7.0)];
+        assert_eq!(values(3, &records), vec![3.0, 3.0, 4.0, 8.0, 8.0]);
+    }
+
+    #[test]
+    fn huge_windows_do_not_allocate_up_front() {
+        assert_eq!(
+            values(usize::MAX, &[("a", 1.0), ("a", 3.0)]),
+            vec![2.0, 2.0]
+        );
+    }
+
+    #[test]
+    fn halves_give_the_median_of_a_sliding_window() {
+        let values = [5.0, 1.0, 4.0, 4.0, -2.0, 9.0, 4.0, 0.5, 7.0, 3.0, 3.0, 8.0];
+        let mut halves = Halves::default();
+        for (index, &value) in values.iter().enumerate() {
+            halves.insert(value);
+            if index >= 4 {
+                halves.remove(values[index - 4]);
+            }
+            let window = &values[index.saturating_sub(3)..=index];
+            let mut sorted = window.to_vec();
+            sorted.sort_by(f64::total_cmp);
+            let middle = sorted.len() / 2;
+            let expected = match sorted.len() % 2 {
+                0 => (sorted[middle - 1] + sorted[middle]) / 2.0,
+                _ => sorted[middle],
+            };
+            assert_eq!(halves.median(), expected, "window {:?}", window);
+        }
+    }
+}
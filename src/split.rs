@@ -0,0 +1,243 @@
+use crate::bed::{is_header_line, MaybeCompressed};
+use crate::{
+    BedGraphRecord, BedParser, ConvertOptions, ConvertStats, Converter, Error, RecordWriter,
+};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::sync::Mutex;
+
+/// The input lines of one chromosome and the line numbers they had in the input
+struct Partition {
+    chrom: String,
+    lines: Vec<u8>,
+    line_numbers: Vec<usize>,
+}
+
+impl Partition {
+    /// Points errors at the input line instead of the line within the partition
+    fn map_line(&self, error: Error) -> Error {
+        let original = |line: usize| {
+            let index = line.checked_sub(1);
+            index
+                .and_then(|i| self.line_numbers.get(i))
+                .copied()
+                .unwrap_or(line)
+        };
+        match error {
+            Error::Parse { line, message } => Error::parse(original(line), message),
+            Error::Unsorted { line, message } => Error::unsorted(original(line), message),
+            error => error,
+        }
+    }
+}
+
+/// Drops what it is given, for a converter that only checks which records have a value
+struct Discard;
+
+impl RecordWriter for Discard {
+    fn write(&mut self, _: &BedGraphRecord) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Splits the input lines by chromosome, in the order chromosomes first appear, leaving
+/// out the chromosomes `opts.chroms` drops. Names that `opts.chrom_prefix` renames to the
+/// same chromosome share a partition. `opts.head` counts the records of all partitions
+/// that have a value, as it would for the input as a whole
+fn partition<R: BufRead>(reader: R, opts: &ConvertOptions) -> Result<Vec<Partition>, Error> {
+    let reader = MaybeCompressed::new(reader)?;
+    let mut partitions: Vec<Partition> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut kept: u64 = 0;
+    // Only needed to tell which records use up the head. Lines it fails on are left for
+    // the conversion of their partition to report
+    let probe_opts = ConvertOptions {
+        check_sorted: false,
+        assume_sorted: false,
+        ..opts.clone()
+    };
+    let mut probe = opts
+        .head
+        .map(|_| Converter::with_writer(Discard, &probe_opts));
+    for (line_index, line) in reader.lines().enumerate() {
+        if opts.head.is_some_and(|head| kept >= head) {
+            break;
+        }
+        let line = line.map_err(Error::Read)?;
+        if is_header_line(&line) {
+            continue;
+        }
+        let mut chrom = line
+            .split('\t')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        if let Some(prefix) = opts.chrom_prefix {
+            prefix.apply(&mut chrom);
+        }
+        if opts
+            .chroms
+            .as_ref()
+            .is_some_and(|chroms| !chroms.keeps(&chrom))
+        {
+            continue;
+        }
+        if let Some(probe) = probe.as_mut() {
+            let record = BedParser::with_default_score(line.as_bytes(), opts.default_score).next();
+            if let Some(Ok(record)) = record {
+                if let Ok(Some(_)) = probe.prepare(record, line_index + 1, opts.scale) {
+                    kept += 1;
+                }
+            }
+        }
+        let partition = match index.get(&chrom) {
+            Some(&i) => &mut partitions[i],
+            None => {
+                index.insert(chrom.clone(), partitions.len());
+                partitions.push(Partition {
+                    chrom: chrom.clone(),
+                    lines: Vec::new(),
+                    line_numbers: Vec::new(),
+                });
+                partitions.last_mut().expect("a partition was just added")
+            }
+        };
+        partition.lines.extend(line.as_bytes());
+        partition.lines.push(b'\n');
+        partition.line_numbers.push(line_index + 1);
+    }
+    Ok(partitions)
+}
+
+/// Converts each chromosome of the input into its own bedGraph track, with
+/// `create_writer` opening the output for a chromosome. The input is held in memory
+/// while up to `threads` chromosomes are converted at once
+pub fn convert_split<R, W, F>(
+    reader: R,
+    create_writer: F,
+    threads: usize,
+    opts: &ConvertOptions,
+) -> Result<Vec<(String, ConvertStats)>, Error>
+where
+    R: BufRead,
+    W: Write,
+    F: Fn(&str) -> Result<W, Error> + Sync,
+{
+    let partitions = partition(reader, opts)?;
+    // The head was taken while partitioning
+    let opts = &ConvertOptions {
+        head: None,
+        ..opts.clone()
+    };
+
+    let queue = Mutex::new(partitions.iter().enumerate());
+    let results = Mutex::new(Vec::with_capacity(partitions.len()));
+    let convert_one = |partition: &Partition| -> Result<ConvertStats, Error> {
+        let writer = create_writer(&partition.chrom)?;
+        let mut converter = Converter::new(writer, opts)?;
+        converter
+            .add_input(partition.lines.as_slice(), 1.0)
+            .map_err(|e| partition.map_line(e))?;
+        converter.finish()
+    };
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, partitions.len().max(1)) {
+            scope.spawn(|| loop {
+                let Some((index, partition)) = queue.lock().expect("queue lock poisoned").next()
+                else {
+                    break;
+                };
+                let result = convert_one(partition);
+                let failed = result.is_err();
+                results
+                    .lock()
+                    .expect("results lock poisoned")
+                    .push((index, result));
+                if failed {
+                    break;
+                }
+            });
+        }
+    });
+
+    // Report in input order, failing with the first chromosome that failed
+    let mut results = results.into_inner().expect("results lock poisoned");
+    results.sort_by_key(|(index, _)| *index);
+    results
+        .into_iter()
+        .map(|(index, result)| result.map(|stats| (partitions[index].chrom.clone(), stats)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn head_counts_records_across_chromosomes() {
+        let input = "a\t0\t1\t.\t1\nb\t0\t1\t.\t2\na\t2\t3\t.\t3\nb\t4\t5\t.\t4\n";
+        let opts = ConvertOptions {
+            value: crate::ValueSource::Score,
+            header: None,
+            head: Some(3),
+            ..ConvertOptions::default()
+        };
+        let outputs = Mutex::new(HashMap::new());
+        let create_writer = |chrom: &str| {
+            outputs
+                .lock()
+                .unwrap()
+                .insert(chrom.to_string(), Vec::new());
+            Ok(Buffer(chrom.to_string(), &outputs))
+        };
+        let stats = convert_split(input.as_bytes(), create_writer, 2, &opts).unwrap();
+        assert_eq!(stats.iter().map(|(_, s)| s.records).sum::<u64>(), 3);
+        let outputs = outputs.into_inner().unwrap();
+        assert_eq!(outputs["a"], b"a\t0\t1\t1\na\t2\t3\t3\n");
+        assert_eq!(outputs["b"], b"b\t0\t1\t2\n");
+    }
+
+    #[test]
+    fn head_counts_only_records_with_a_value() {
+        let input = "a\t0\t1\t.\t0\nb\t0\t1\t.\t0\t2\na\t2\t3\t.\t0\t3\nb\t4\t5\t.\t0\t4\n";
+        let opts = ConvertOptions {
+            missing_column: crate::MissingColumnAction::Skip,
+            header: None,
+            head: Some(2),
+            ..ConvertOptions::default()
+        };
+        let outputs = Mutex::new(HashMap::new());
+        let create_writer = |chrom: &str| {
+            outputs
+                .lock()
+                .unwrap()
+                .insert(chrom.to_string(), Vec::new());
+            Ok(Buffer(chrom.to_string(), &outputs))
+        };
+        convert_split(input.as_bytes(), create_writer, 2, &opts).unwrap();
+        let outputs = outputs.into_inner().unwrap();
+        assert_eq!(outputs["a"], b"a\t2\t3\t3\n");
+        assert_eq!(outputs["b"], b"b\t0\t1\t2\n");
+    }
+
+    /// Appends what is written to the output of a chromosome
+    struct Buffer<'a>(String, &'a Mutex<HashMap<String, Vec<u8>>>);
+
+    impl Write for Buffer<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut outputs = self.1.lock().unwrap();
+            outputs.get_mut(&self.0).unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
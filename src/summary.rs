@@ -0,0 +1,171 @@
+use crate::{bases, BedGraphRecord};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// How a [`Summary`] is written
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SummaryFormat {
+    /// Aligned columns for reading
+    #[default]
+    Table,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+/// Statistics over the values of the written records of one chromosome, or of all of them
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChromSummary {
+    pub chrom: String,
+    pub count: u64,
+    pub covered_bases: u64,
+    pub min: f64,
+    pub max: f64,
+    weighted_sum: f64,
+    values: Vec<f64>,
+}
+
+impl ChromSummary {
+    fn new(chrom: String) -> Self {
+        ChromSummary {
+            chrom,
+            count: 0,
+            covered_bases: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            weighted_sum: 0.0,
+            values: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, value: f64, bases: u64) {
+        self.count += 1;
+        self.covered_bases += bases;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.weighted_sum += value * bases as f64;
+        self.values.push(value);
+    }
+
+    fn extend(&mut self, other: &ChromSummary) {
+        self.count += other.count;
+        self.covered_bases += other.covered_bases;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.weighted_sum += other.weighted_sum;
+        self.values.extend(&other.values);
+    }
+
+    /// The mean value per base
+    pub fn mean(&self) -> f64 {
+        self.weighted_sum / self.covered_bases as f64
+    }
+
+    /// The median of the record values, each record counting once
+    pub fn median(&self) -> f64 {
+        let mut values = self.values.clone();
+        values.sort_by(f64::total_cmp);
+        let middle = values.len() / 2;
+        match values.len() {
+            0 => f64::NAN,
+            len if len % 2 == 0 => (values[middle - 1] + values[middle]) / 2.0,
+            _ => values[middle],
+        }
+    }
+}
+
+/// Per-chromosome statistics of the written records, in the order chromosomes were first
+/// written. Every value is kept for the median
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    chroms: Vec<ChromSummary>,
+    index: HashMap<String, usize>,
+}
+
+impl Summary {
+    pub(crate) fn add(&mut self, record: &BedGraphRecord) {
+        let bases = bases(record.start, record.end);
+        self.chrom_mut(&record.chrom).add(record.value, bases);
+    }
+
+    fn chrom_mut(&mut self, chrom: &str) -> &mut ChromSummary {
+        let index = match self.index.get(chrom) {
+            Some(&index) => index,
+            None => {
+                self.index.insert(chrom.to_string(), self.chroms.len());
+                self.chroms.push(ChromSummary::new(chrom.to_string()));
+                self.chroms.len() - 1
+            }
+        };
+        &mut self.chroms[index]
+    }
+
+    /// Adds the statistics of another part of the output
+    pub fn extend(&mut self, other: &Summary) {
+        for chrom in &other.chroms {
+            self.chrom_mut(&chrom.chrom).extend(chrom);
+        }
+    }
+
+    pub fn chroms(&self) -> &[ChromSummary] {
+        &self.chroms
+    }
+
+    /// The statistics over every chromosome, named `all`
+    pub fn overall(&self) -> ChromSummary {
+        let mut overall = ChromSummary::new("all".to_string());
+        for chrom in &self.chroms {
+            overall.extend(chrom);
+        }
+        overall
+    }
+
+    /// Writes a row per chromosome followed by the overall row
+    pub fn write<W: Write>(&self, mut writer: W, format: SummaryFormat) -> std::io::Result<()> {
+        let overall = self.overall();
+        let rows = self.chroms.iter().chain(std::iter::once(&overall));
+        match format {
+            SummaryFormat::Csv => {
+                writeln!(writer, "chrom,count,covered_bases,min,max,mean,median")?;
+                for row in rows {
+                    writeln!(
+                        writer,
+                        "{},{},{},{},{},{},{}",
+                        row.chrom,
+                        row.count,
+                        row.covered_bases,
+                        row.min,
+                        row.max,
+                        row.mean(),
+                        row.median()
+                    )?;
+                }
+            }
+            SummaryFormat::Table => {
+                let width = rows
+                    .clone()
+                    .map(|row| row.chrom.len())
+                    .fold("chrom".len(), usize::max);
+                writeln!(
+                    writer,
+                    "{:<width$}  {:>10}  {:>13}  {:>12}  {:>12}  {:>12}  {:>12}",
+                    "chrom", "count", "covered_bases", "min", "max", "mean", "median"
+                )?;
+                for row in rows {
+                    writeln!(
+                        writer,
+                        "{:<width$}  {:>10}  {:>13}  {:>12.4}  {:>12.4}  {:>12.4}  {:>12.4}",
+                        row.chrom,
+                        row.count,
+                        row.covered_bases,
+                        row.min,
+                        row.max,
+                        row.mean(),
+                        row.median()
+                    )?;
+                }
+            }
+        }
+        writer.flush()
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{BedGraphRecord, Error, Stage};
+use std::collections::VecDeque;
+
+/// Passes on only the last `count` records, holding at most that many at a time
+pub(crate) struct Tail {
+    count: usize,
+    records: VecDeque<BedGraphRecord>,
+}
+
+impl Tail {
+    pub(crate) fn new(count: usize) -> Self {
+        Tail {
+            count,
+            records: VecDeque::new(),
+        }
+    }
+}
+
+impl Stage for Tail {
+    fn push(&mut self, record: BedGraphRecord, _: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        if self.count == 0 {
+            return Ok(());
+        }
+        if self.records.len() == self.count {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<BedGraphRecord>) -> Result<(), Error> {
+        out.extend(self.records.drain(..));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::record;
+    use crate::Coord;
+
+    fn starts(count: usize, records: usize) -> Vec<Coord> {
+        let mut tail = Tail::new(count);
+        let mut out = Vec::new();
+        for start in 0..records as Coord {
+            tail.push(record("c", start * 10, start * 10 + 10, 1.0), &mut out)
+                .unwrap();
+        }
+        tail.finish(&mut out).unwrap();
+        out.iter().map(|record| record.start).collect()
+    }
+
+    #[test]
+    fn keeps_the_last_records() {
+        assert_eq!(starts(2, 4), vec![20, 30]);
+        assert_eq!(starts(0, 4), vec![]);
+    }
+
+    #[test]
+    fn huge_counts_do_not_allocate_up_front() {
+        assert_eq!(starts(usize::MAX, 2), vec![0, 10]);
+    }
+}
@@ -0,0 +1,34 @@
+//! Helpers shared by the unit tests of the stages and writers
+
+use crate::{BedGraphRecord, BedRecord, Coord};
+
+/// A record without BED columns
+pub(crate) fn record(chrom: &str, start: Coord, end: Coord, value: f64) -> BedGraphRecord {
+    BedGraphRecord {
+        chrom: chrom.to_string(),
+        start,
+        end,
+        value,
+        columns: None,
+    }
+}
+
+/// A BED record named `n` with a score of 0
+pub(crate) fn bed_record(chrom: &str, start: Coord, end: Coord, values: &[&str]) -> BedRecord {
+    BedRecord {
+        chrom: chrom.to_string(),
+        start,
+        end,
+        name: "n".to_string(),
+        score: 0.0,
+        values: values.iter().map(|value| value.to_string()).collect(),
+    }
+}
+
+/// The coordinates and value of each record, for comparing what a stage passed on
+pub(crate) fn spans(records: &[BedGraphRecord]) -> Vec<(Coord, Coord, f64)> {
+    records
+        .iter()
+        .map(|record| (record.start, record.end, record.value))
+        .collect()
+}
@@ -0,0 +1,138 @@
+use crate::BedGraphRecord;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Value,
+    Start,
+    End,
+    Length,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    Min,
+    Max,
+    Ln,
+    Log2,
+    Abs,
+}
+
+impl Token {
+    /// How many values the token takes off the stack
+    fn arity(self) -> usize {
+        match self {
+            Token::Number(_) | Token::Value | Token::Start | Token::End | Token::Length => 0,
+            Token::Ln | Token::Log2 | Token::Abs => 1,
+            Token::Add
+            | Token::Sub
+            | Token::Mul
+            | Token::Div
+            | Token::Pow
+            | Token::Min
+            | Token::Max => 2,
+        }
+    }
+}
+
+/// A per-record expression in reverse Polish notation, like `value 1 + ln 2 *`.
+/// `value`, `start`, `end` and `length` push a property of the record; `+ - * / pow min
+/// max` take two values and `ln log2 abs` take one. The expression is checked when it is
+/// parsed so that evaluating it cannot fail
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transform {
+    tokens: Vec<Token>,
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+        for word in expression.split_whitespace() {
+            let token = match word {
+                "value" => Token::Value,
+                "start" => Token::Start,
+                "end" => Token::End,
+                "length" => Token::Length,
+                "+" => Token::Add,
+                "-" => Token::Sub,
+                "*" => Token::Mul,
+                "/" => Token::Div,
+                "pow" => Token::Pow,
+                "min" => Token::Min,
+                "max" => Token::Max,
+                "ln" => Token::Ln,
+                "log2" => Token::Log2,
+                "abs" => Token::Abs,
+                number => Token::Number(
+                    number
+                        .parse::<f64>()
+                        .map_err(|_| format!("Unknown word '{}' in transform", word))?,
+                ),
+            };
+            if depth < token.arity() {
+                return Err(format!(
+                    "'{}' needs {} values but the stack only has {}",
+                    word,
+                    token.arity(),
+                    depth
+                ));
+            }
+            depth = depth - token.arity() + 1;
+            tokens.push(token);
+        }
+        match depth {
+            1 => Ok(Transform { tokens }),
+            0 => Err("Transform is empty".to_string()),
+            depth => Err(format!(
+                "Transform leaves {} values on the stack instead of 1",
+                depth
+            )),
+        }
+    }
+}
+
+impl Transform {
+    /// Works out the new value of a record
+    pub fn apply(&self, record: &BedGraphRecord) -> f64 {
+        let mut stack: Vec<f64> = Vec::with_capacity(self.tokens.len());
+        let pop = |stack: &mut Vec<f64>| stack.pop().expect("checked when parsed");
+        for &token in &self.tokens {
+            let value = match token {
+                Token::Number(number) => number,
+                Token::Value => record.value,
+                Token::Start => record.start as f64,
+                Token::End => record.end as f64,
+                Token::Length => record.end.saturating_sub(record.start) as f64,
+                Token::Ln => pop(&mut stack).ln(),
+                Token::Log2 => pop(&mut stack).log2(),
+                Token::Abs => pop(&mut stack).abs(),
+                Token::Add
+                | Token::Sub
+                | Token::Mul
+                | Token::Div
+                | Token::Pow
+                | Token::Min
+                | Token::Max => {
+                    let b = pop(&mut stack);
+                    let a = pop(&mut stack);
+                    match token {
+                        Token::Add => a + b,
+                        Token::Sub => a - b,
+                        Token::Mul => a * b,
+                        Token::Div => a / b,
+                        Token::Pow => a.powf(b),
+                        Token::Min => a.min(b),
+                        _ => a.max(b),
+                    }
+                }
+            };
+            stack.push(value);
+        }
+        pop(&mut stack)
+    }
+}
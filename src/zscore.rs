@@ -0,0 +1,52 @@
+use crate::BedGraphRecord;
+
+/// The mean and standard deviation of the values that were z-scored
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Moments {
+    pub mean: f64,
+    /// The population standard deviation
+    pub stddev: f64,
+}
+
+/// Holds every output record back while keeping a running mean and variance with
+/// Welford's algorithm, which stays accurate where a sum of squares would not
+#[derive(Default)]
+pub(crate) struct ZScorer {
+    records: Vec<BedGraphRecord>,
+    mean: f64,
+    m2: f64,
+}
+
+impl ZScorer {
+    pub(crate) fn push(&mut self, record: BedGraphRecord) {
+        let count = (self.records.len() + 1) as f64;
+        let delta = record.value - self.mean;
+        self.mean += delta / count;
+        self.m2 += delta * (record.value - self.mean);
+        self.records.push(record);
+    }
+
+    pub(crate) fn moments(&self) -> Moments {
+        match self.records.len() {
+            0 => Moments::default(),
+            count => Moments {
+                mean: self.mean,
+                stddev: (self.m2 / count as f64).sqrt(),
+            },
+        }
+    }
+
+    /// Hands back the records with their values replaced by z-scores, all 0 when every
+    /// value is the same
+    pub(crate) fn finish(self) -> (Moments, Vec<BedGraphRecord>) {
+        let moments = self.moments();
+        let mut records = self.records;
+        for record in &mut records {
+            record.value = match moments.stddev {
+                0.0 => 0.0,
+                stddev => (record.value - moments.mean) / stddev,
+            };
+        }
+        (moments, records)
+    }
+}
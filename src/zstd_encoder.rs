@@ -0,0 +1,111 @@
+use std::io::{self, Write};
+use zstd::stream::write::Encoder;
+
+/// Zstd-compresses what is written to it. Flushing ends the current frame and hands back
+/// any error from ending it, which an encoder that finishes itself on drop can only
+/// ignore. Writing after a flush starts a new frame, and zstd readers read all of the
+/// frames back as a single stream
+pub struct ZstdEncoder<W: Write> {
+    level: i32,
+    /// Between a flush and the next write, the frame is finished and only `writer` is set
+    encoder: Option<Encoder<'static, W>>,
+    writer: Option<W>,
+}
+
+impl<W: Write> ZstdEncoder<W> {
+    pub fn new(writer: W, level: i32) -> io::Result<Self> {
+        Ok(ZstdEncoder {
+            level,
+            encoder: Some(Encoder::new(writer, level)?),
+            writer: None,
+        })
+    }
+
+    fn encoder(&mut self) -> io::Result<&mut Encoder<'static, W>> {
+        if self.encoder.is_none() {
+            let writer = self
+                .writer
+                .take()
+                .ok_or_else(|| io::Error::other("a zstd frame could not be started"))?;
+            self.encoder = Some(Encoder::new(writer, self.level)?);
+        }
+        Ok(self.encoder.as_mut().expect("the encoder was just started"))
+    }
+}
+
+impl<W: Write> Write for ZstdEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(encoder) = self.encoder.take() {
+            self.writer = Some(encoder.finish()?);
+        }
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Err(io::Error::other("the zstd frame could not be finished")),
+        }
+    }
+}
+
+impl<W: Write> Drop for ZstdEncoder<W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn decompress(compressed: &[u8]) -> Vec<u8> {
+        let mut decompressed = Vec::new();
+        zstd::Decoder::new(compressed)
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        decompressed
+    }
+
+    #[test]
+    fn writes_after_a_flush_go_into_another_frame() {
+        let mut compressed = Vec::new();
+        {
+            let mut encoder = ZstdEncoder::new(&mut compressed, 3).unwrap();
+            encoder.write_all(b"c\t0\t10\t1\n").unwrap();
+            encoder.flush().unwrap();
+            encoder.write_all(b"c\t10\t20\t2\n").unwrap();
+            encoder.flush().unwrap();
+        }
+        assert_eq!(decompress(&compressed), b"c\t0\t10\t1\nc\t10\t20\t2\n");
+    }
+
+    #[test]
+    fn empty_output_is_a_valid_frame() {
+        let mut compressed = Vec::new();
+        ZstdEncoder::new(&mut compressed, 3)
+            .unwrap()
+            .flush()
+            .unwrap();
+        assert!(!compressed.is_empty());
+        assert_eq!(decompress(&compressed), b"");
+    }
+
+    #[test]
+    fn frame_end_errors_are_returned_by_flush() {
+        struct Full;
+        impl Write for Full {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::new(io::ErrorKind::StorageFull, "disk full"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut encoder = ZstdEncoder::new(Full, 3).unwrap();
+        encoder.write_all(b"c\t0\t10\t1\n").unwrap();
+        assert!(encoder.flush().is_err());
+    }
+}